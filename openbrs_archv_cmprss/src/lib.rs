@@ -1,91 +1,318 @@
+use flate2::{read::GzDecoder, write::GzEncoder};
+use lz4_flex::frame::{FrameDecoder, FrameEncoder};
+use openbrs_crypto::{DecryptReader, EncryptWriter, RepoKey};
+use openbrs_error::{OpenBrsError, Result, WithPath};
+use openbrs_pack::ObjectStore;
+use serde::{Deserialize, Serialize};
 use std::{
+    fmt,
     fs::{self, File},
-    path::{self, PathBuf},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
 };
-use tar::Builder;
-use xz::write::XzEncoder;
-
-pub fn archive_compress(target_path: &PathBuf, blobs: &PathBuf) {
-    // Set the path to archive:
-    let archive_name = format!(
-        "{}.tar.xz",
-        target_path.file_name().unwrap().to_str().unwrap()
-    );
-    let archive = blobs.join(format!("{archive_name}"));
-
-    // Create the file before turning it to an archive
-    let archive_file = File::create(archive).unwrap();
-
-    // create an XzEncoder that wraps the file (this implements Write)
-    // Thus, we can compress on the fly
-    let encoder = XzEncoder::new(archive_file, 9); // 0..9 compression level
-
-    // Build the archive to stream INTO the encoder to compress it directly
-    let mut archive = Builder::new(encoder);
-
-    // add a file to the archive
-    if target_path.is_dir() {
-        // First, register the target directory, then archive its content
-        let dir_name = target_path.file_name().unwrap();
-        archive.append_dir(dir_name, target_path).unwrap();
-        append_dir_all_excluding(&mut archive, target_path, target_path);
-    } else {
-        archive.append_path(target_path).unwrap();
+use xz::{read::XzDecoder, write::XzEncoder};
+
+/// The codecs chunks can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+    Xz,
+    Gzip,
+    Lz4,
+    None, // Stored as it is
+}
+
+impl Codec {
+    // The byte each chunk starts with, so that it's read back with the codec it was written with
+    fn tag(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Xz => 1,
+            Codec::Zstd => 2,
+            Codec::Gzip => 3,
+            Codec::Lz4 => 4,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Xz),
+            2 => Some(Codec::Zstd),
+            3 => Some(Codec::Gzip),
+            4 => Some(Codec::Lz4),
+            _ => None,
+        }
+    }
+
+    // The levels the codec takes, and the one it's used at unless told otherwise
+    fn levels(self) -> Option<(i32, i32, i32)> {
+        match self {
+            Codec::Zstd => Some((1, 22, 3)),
+            Codec::Xz => Some((0, 9, 6)),
+            Codec::Gzip => Some((0, 9, 6)),
+            Codec::Lz4 | Codec::None => None,
+        }
     }
+}
 
-    // finish the tar stream
-    archive.finish().unwrap();
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Codec::Zstd => "zstd",
+            Codec::Xz => "xz",
+            Codec::Gzip => "gzip",
+            Codec::Lz4 => "lz4",
+            Codec::None => "none",
+        };
+        f.write_str(name)
+    }
+}
 
-    // Unwrap this archive, returning the underlying object which is the compressed data.
-    let encoder = archive.into_inner().unwrap();
+/// The extensions of files that are compressed already, stored as they are unless the repository's
+/// config.toml lists others
+pub const DEFAULT_INCOMPRESSIBLE: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "mp3", "ogg", "flac", "aac", "mp4", "mkv", "mov",
+    "webm", "avi", "zip", "gz", "tgz", "xz", "bz2", "zst", "lz4", "7z", "rar", "jar", "docx",
+    "xlsx", "pptx", "odt", "pdf", "gpg", "age",
+];
 
-    // finish compression and get the inner File back
-    let file = encoder.finish().unwrap();
+// How much of a file its content is judged on, and the entropy, in bits per byte, above which it
+// looks random enough not to shrink meaningfully
+const SAMPLE_SIZE: usize = 64 * 1024;
+const MAX_ENTROPY: f64 = 7.5;
 
-    // ensure data is flushed to disk
-    file.sync_all().unwrap();
+/// How new chunks are compressed; a repository's config.toml may set it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Compression {
+    pub codec: Codec,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub level: Option<i32>, // The codec's own default if unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incompressible: Option<Vec<String>>, // DEFAULT_INCOMPRESSIBLE if unset
 }
 
-// I use this function to exclude the .openbrs workspace:
-// Signature explication:
-//  * builder is the Tar builder;
-//  * base is the root file system we're backing up;
-//  * path is the current directory we're visiting during recursion, it starts equal to base.
-fn append_dir_all_excluding(
-    builder: &mut Builder<XzEncoder<File>>,
-    base: &PathBuf,
-    path: &PathBuf,
-) {
-    // What we want to exclude
-    let exclude = ".openbrs";
-
-    // Is it a directory, or a file?
-    if path.is_dir() {
-        // iterate through entries and get the the entry's name
-        for entry in fs::read_dir(path).unwrap() {
-            let entry = entry.unwrap();
-            let file_name = entry.file_name();
-            let file_name_str = file_name.to_string_lossy();
-
-            // Is the target our workspace or does it contain it?
-            if exclude.contains(&file_name_str.as_ref()) {
-                continue;
-            }
+impl Default for Compression {
+    // Fast enough for nightly runs over large targets, and still compresses well
+    fn default() -> Self {
+        Self {
+            codec: Codec::Zstd,
+            level: None,
+            incompressible: None,
+        }
+    }
+}
+
+impl Compression {
+    /// Make sure the codec takes the level
+    pub fn check(&self) -> Result<()> {
+        let Some(level) = self.level else {
+            return Ok(());
+        };
+        match self.codec.levels() {
+            Some((min, max, _)) if (min..=max).contains(&level) => Ok(()),
+            Some((min, max, _)) => Err(OpenBrsError::CompressionLevel {
+                codec: self.codec.to_string(),
+                level,
+                levels: format!("its levels go from {min} to {max}"),
+            }),
+            None => Err(OpenBrsError::CompressionLevel {
+                codec: self.codec.to_string(),
+                level,
+                levels: String::from("it takes none"),
+            }),
+        }
+    }
+
+    /// Whether the file at `path` is worth compressing, judging by its extension, then by the
+    /// entropy of `sample`, the start of its content
+    pub fn compresses(&self, path: &Path, sample: &[u8]) -> bool {
+        if self.codec == Codec::None {
+            return false;
+        }
 
-            // Get the full and relative paths
-            let entry_path = entry.path();
-            let rel_path = path::Path::new(base.file_name().unwrap())
-                .join(entry_path.strip_prefix(base).unwrap());
-
-            // is it a folder? Then recurse deeper; otherwise, simply archive it
-            if entry_path.is_dir() {
-                builder.append_dir(rel_path, &entry_path).unwrap();
-                append_dir_all_excluding(builder, base, &entry_path);
-            } else {
-                builder
-                    .append_path_with_name(&entry_path, rel_path)
-                    .unwrap();
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if let Some(extension) = extension {
+            let listed = match &self.incompressible {
+                Some(list) => list
+                    .iter()
+                    .any(|listed| listed.eq_ignore_ascii_case(&extension)),
+                None => DEFAULT_INCOMPRESSIBLE.contains(&extension.as_str()),
+            };
+            if listed {
+                return false;
             }
         }
+
+        entropy(&sample[..sample.len().min(SAMPLE_SIZE)]) < MAX_ENTROPY
+    }
+
+    fn level(&self) -> i32 {
+        let default = self.codec.levels().map_or(0, |(_, _, default)| default);
+        self.level.unwrap_or(default)
+    }
+}
+
+/// Where the chunk `id` is stored; chunks of encrypted repositories end in `.enc`
+pub fn chunk_path(chunks: &Path, id: &str, encrypted: bool) -> PathBuf {
+    match encrypted {
+        true => chunks.join(format!("{id}.chunk.enc")),
+        false => chunks.join(format!("{id}.chunk")),
+    }
+}
+
+/// Compress a chunk of a file into `<id>.chunk`, where `id` is its chunk ID, or store it as it is
+/// without `compression`. The codec it was compressed with, if any, is recorded first.
+/// With a key, the chunk is encrypted as it is written, to `<id>.chunk.enc`.
+/// Chunks are named after their content, so one that exists already, loose or packed, is left as
+/// it is.
+pub fn store_chunk(
+    content: &[u8],
+    store: &ObjectStore,
+    chunks: &Path,
+    id: &str,
+    compression: Option<&Compression>,
+    key: Option<&RepoKey>,
+) -> Result<()> {
+    let chunk_path = chunk_path(chunks, id, key.is_some());
+    if store.exists(&chunk_path)? {
+        return Ok(());
     }
+
+    // Write to a temporary file, and only give it its name once it's complete: a chunk that
+    // exists must be whole.
+    let mut tmp_path = chunk_path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let chunk_file = File::create(&tmp_path).at(&tmp_path)?;
+
+    let file = match key {
+        Some(key) => {
+            // The plaintext never touches the disk
+            let writer = EncryptWriter::new(chunk_file, key).at(&tmp_path)?;
+            compress(writer, content, compression)
+                .at(&tmp_path)?
+                .finish()
+                .at(&tmp_path)?
+        }
+        None => compress(chunk_file, content, compression).at(&tmp_path)?,
+    };
+
+    // ensure data is flushed to disk
+    file.sync_all().at(&tmp_path)?;
+
+    fs::rename(&tmp_path, &chunk_path).at(&chunk_path)
+}
+
+/// Read back the content of the chunk `id`, decrypting it with `key` in an encrypted repository
+pub fn read_chunk(
+    store: &ObjectStore,
+    chunks: &Path,
+    id: &str,
+    key: Option<&RepoKey>,
+) -> Result<Vec<u8>> {
+    let chunk_path = chunk_path(chunks, id, key.is_some());
+    let object = match store.open(&chunk_path) {
+        Ok(object) => object,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(OpenBrsError::MissingObject {
+                kind: "chunk",
+                id: id.to_string(),
+            });
+        }
+        Err(e) => return Err(e).at(&chunk_path),
+    };
+
+    // An encrypted chunk is authenticated before it is decompressed
+    let reader: Box<dyn Read> = match key {
+        Some(key) => Box::new(DecryptReader::new(object, key).at(&chunk_path)?),
+        None => object,
+    };
+
+    let mut content = Vec::new();
+    match decompress(reader).and_then(|mut reader| reader.read_to_end(&mut content)) {
+        Ok(_) => Ok(content),
+        Err(e) if e.kind() == ErrorKind::InvalidData => Err(OpenBrsError::Corrupted(chunk_path)),
+        Err(e) => Err(e).at(&chunk_path),
+    }
+}
+
+// Compress `content` into `writer`, after the codec's tag, and hand the writer back once the
+// stream is finished
+fn compress<W: Write>(
+    mut writer: W,
+    content: &[u8],
+    compression: Option<&Compression>,
+) -> io::Result<W> {
+    let (codec, level) = compression.map_or((Codec::None, 0), |c| (c.codec, c.level()));
+    writer.write_all(&[codec.tag()])?;
+
+    match codec {
+        Codec::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, level)?;
+            encoder.write_all(content)?;
+            encoder.finish()
+        }
+        Codec::Xz => {
+            let mut encoder = XzEncoder::new(writer, level as u32);
+            encoder.write_all(content)?;
+            encoder.finish()
+        }
+        Codec::Gzip => {
+            let mut encoder = GzEncoder::new(writer, flate2::Compression::new(level as u32));
+            encoder.write_all(content)?;
+            encoder.finish()
+        }
+        Codec::Lz4 => {
+            let mut encoder = FrameEncoder::new(writer);
+            encoder.write_all(content)?;
+            encoder.finish().map_err(io::Error::other)
+        }
+        Codec::None => {
+            writer.write_all(content)?;
+            Ok(writer)
+        }
+    }
+}
+
+// Shannon entropy of the bytes of `sample`, from 0 for a repeated byte to 8 for random ones
+fn entropy(sample: &[u8]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0usize; 256];
+    for &byte in sample {
+        counts[byte as usize] += 1;
+    }
+
+    let len = sample.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Read the codec's tag, and decompress what follows with it
+fn decompress(mut reader: Box<dyn Read>) -> io::Result<Box<dyn Read>> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+
+    let codec = Codec::from_tag(tag[0])
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "unknown codec"))?;
+    Ok(match codec {
+        Codec::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Codec::Xz => Box::new(XzDecoder::new(reader)),
+        Codec::Gzip => Box::new(GzDecoder::new(reader)),
+        Codec::Lz4 => Box::new(FrameDecoder::new(reader)),
+        Codec::None => reader,
+    })
 }
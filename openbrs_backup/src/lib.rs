@@ -1,73 +1,134 @@
-use openbrs_archv_cmprss::archive_compress;
+use openbrs_archv_cmprss::Compression;
 use openbrs_compare::compare_trees;
-use openbrs_main_structs::{Commit, FilePath, Tree};
-use openbrs_stage::stage;
-use serde_json;
-use std::fs;
+use openbrs_crypto::{CommitSigner, RepoKey};
+use openbrs_error::{OpenBrsError, Result};
+use openbrs_main_structs::{Commit, FilePath, RepoConfig, Tree};
+use openbrs_stage::{stage, stage_tree};
 
 // Function to run a full backup.
-pub fn backup_full(paths: &FilePath) {
-    let tree = Tree::build(paths, true);
+// Both backups return the files they had to skip; anything else that goes wrong aborts the backup
+// before HEAD moves.
+// New chunks are compressed as `compression` says.
+// In an encrypted repository, `key` encrypts the blobs, and the trees and commits.
+// The commit is signed with `signer`, if any.
+pub fn backup_full(
+    paths: &FilePath,
+    compression: &Compression,
+    signer: Option<&CommitSigner>,
+    key: Option<&RepoKey>,
+) -> Result<Vec<OpenBrsError>> {
+    let mut skipped = Vec::new();
+    let previous = head_tree(paths, key)?;
+    let tree = Tree::build(paths, previous.as_ref(), &mut skipped, key)?;
 
     // Write off the tree as a JSON
-    tree.write_tree(&paths);
+    tree.write_tree(paths, key)?;
 
-    // Stage the backup
-    archive_compress(&paths.target, &paths.blobs);
+    // Store every file whose blob isn't stored yet
+    skipped.extend(stage_tree(&tree, paths, compression, key)?);
 
-    // Make the commit which will point to the blob and tree.
+    // Make the commit which will point to the tree.
     // If the work is not committed, it'll be some trash that may need to be cleaned later
-    let commit = Commit::new(tree.id, None, String::from("First commit"));
+    // A full backup made over an existing history still follows it, so that the log stays linear
+    let mut commit = Commit::new(
+        tree.id,
+        paths.read_head()?,
+        String::from("Full backup"),
+        key,
+    );
+    if let Some(signer) = signer {
+        commit.sign(signer);
+    }
 
     // Write off the commit as a JSON
-    commit.write(paths);
+    commit.write(paths, key)?;
 
-    // Create the file for the HEAD
-    fs::File::create(&paths.head).unwrap();
+    // Move HEAD to the new commit
+    paths.write_head(&commit.id)?;
 
-    // Write off the commit's ID
-    fs::write(&paths.head, commit.id).unwrap();
+    pack_objects(paths)?;
+
+    Ok(skipped)
 }
 
-pub fn backup_diff(paths: &FilePath, first_backup: bool) {
+pub fn backup_diff(
+    paths: &FilePath,
+    first_backup: bool,
+    compression: &Compression,
+    signer: Option<&CommitSigner>,
+    key: Option<&RepoKey>,
+) -> Result<Vec<OpenBrsError>> {
     match first_backup {
         true => {
             // Upon first backup, we run a full backup
-            backup_full(paths)
+            backup_full(paths, compression, signer, key)
         }
         false => {
             // We run a differential backup
-            // Make the backup, this will prepare the tree
-            let new_tree = Tree::build(&paths, false);
-
-            // Write off the tree as a JSON
-            new_tree.write_tree(paths);
-
-            // Read the latest commit's ID before reading its tree
-            let latest_commit = fs::read_to_string(&paths.head).unwrap();
-
-            // Read the latest commit's content, and get the tree's ID
-            let latest_commit =
-                fs::read_to_string(paths.commits.join(format!("{}.json", latest_commit))).unwrap();
-
-            // Convert it to Commit instance
-            let latest_commit: Commit = serde_json::from_str(&latest_commit).ok().unwrap();
+            // Read the latest commit, and its tree
+            let latest_commit_id = paths.read_head()?.ok_or(OpenBrsError::NoBackups)?;
+            let latest_commit = Commit::read(&latest_commit_id, paths, key)?;
+            let old_tree = Tree::read(&latest_commit.tree_id, paths, key)?;
 
-            // Get the tree's ID
-            let latest_tree_id = latest_commit.tree_id;
+            // Make the backup, this will prepare the tree; what can't be read is kept as it was
+            let mut skipped = Vec::new();
+            let new_tree = Tree::build(paths, Some(&old_tree), &mut skipped, key)?;
 
-            // Read the latest tree
-            let latest_tree =
-                fs::read_to_string(paths.trees.join(format!("{}.json", latest_tree_id))).unwrap();
+            // Write off the tree as a JSON
+            new_tree.write_tree(paths, key)?;
 
-            // Convert it to a Tree instance
-            let old_tree: Tree = serde_json::from_str(&latest_tree).unwrap();
+            // Nothing has changed, there is no need for a new commit
+            if old_tree.id == new_tree.id {
+                return Ok(skipped);
+            }
 
             // Compare the two trees, and get what has changed
-            let changes = compare_trees(&old_tree, &new_tree, paths);
+            let changes = compare_trees(&old_tree, &new_tree, paths, key)?;
 
             // Stage changes
-            stage(changes, paths);
+            skipped.extend(stage(changes, paths, compression, key)?);
+
+            // Commit the new snapshot on top of the latest one, only once everything is staged
+            let mut commit = Commit::new(
+                new_tree.id,
+                Some(latest_commit_id),
+                String::from("Differential backup"),
+                key,
+            );
+            if let Some(signer) = signer {
+                commit.sign(signer);
+            }
+            commit.write(paths, key)?;
+
+            // Move HEAD forward, so the next backup is compared against this one
+            paths.write_head(&commit.id)?;
+
+            pack_objects(paths)?;
+
+            Ok(skipped)
         }
+    }
+}
+
+// The tree of HEAD, which a full backup keeps what it can't read from. None before the first
+// backup, or on a host sealing to recipients, which can't read it back.
+fn head_tree(paths: &FilePath, key: Option<&RepoKey>) -> Result<Option<Tree>> {
+    if key.is_some_and(|key| !key.can_decrypt()) {
+        return Ok(None);
+    }
+    let Some(head) = paths.read_head()? else {
+        return Ok(None);
     };
+    let commit = Commit::read(&head, paths, key)?;
+    Tree::read(&commit.tree_id, paths, key).map(Some)
+}
+
+// Gather the objects the backup wrote into packs, once it's committed. Commits stay loose, there's
+// only one per backup.
+fn pack_objects(paths: &FilePath) -> Result<()> {
+    let pack_size = RepoConfig::read(paths)?.pack_size();
+    paths
+        .store
+        .pack_loose(&[&paths.chunks, &paths.blobs, &paths.trees], pack_size)?;
+    Ok(())
 }
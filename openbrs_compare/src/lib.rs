@@ -1,54 +1,48 @@
-use openbrs_main_structs::{Change, ChangeType, FilePath, Tree};
-use serde_json;
-use std::{collections::HashMap, fs};
+use openbrs_crypto::RepoKey;
+use openbrs_error::Result;
+use openbrs_main_structs::{Change, ChangeType, EntryKind, FilePath, Tree};
+use std::collections::HashMap;
 
-pub fn compare_trees(old_tree: &Tree, new_tree: &Tree, paths: &FilePath) -> Vec<Change> {
+// Subtrees are read with `key`, in an encrypted repository
+pub fn compare_trees(
+    old_tree: &Tree,
+    new_tree: &Tree,
+    paths: &FilePath,
+    key: Option<&RepoKey>,
+) -> Result<Vec<Change>> {
     let mut all_changes: Vec<Change> = Vec::new();
 
     // First, compare the current level
-    let level_changes = current_level_diff(&old_tree, &new_tree);
+    let level_changes = current_level_diff(old_tree, new_tree);
 
     // there are any changes
     match level_changes {
         None => {
             // There are no new changes, don't push the directory, rather, return an empty vector.
-            all_changes
+            Ok(all_changes)
         }
         Some(level_changes) => {
             // We now want to cover the changes that occured in the lower level; if any. So, if it is an added directory, a
             // modified/added file, or a removal, I will simply save the change in all_changes.
             for change in level_changes {
                 // If it's a modification (not an addition/removal) to a directory:
-                match (&change.change_type, &change.path.is_dir()) {
-                    (ChangeType::Modified, true) => {
-                        // A directory was changed
-                        if let (Some(old_tree_id), Some(new_tree_id)) =
-                            (&change.old_id, &change.new_id)
-                        {
-                            // Read old tree, then prase it
-                            let old_tree = fs::read_to_string(
-                                paths.trees.join(format!("{}.json", old_tree_id)),
-                            )
-                            .unwrap();
-                            let old_tree: Tree = serde_json::from_str(&old_tree).ok().unwrap();
+                if let (ChangeType::Modified, EntryKind::Dir) = (&change.change_type, &change.kind)
+                {
+                    // A directory was changed
+                    if let (Some(old_tree_id), Some(new_tree_id)) = (&change.old_id, &change.new_id)
+                    {
+                        // Read both trees, then parse them
+                        let old_tree = Tree::read(old_tree_id, paths, key)?;
+                        let new_tree = Tree::read(new_tree_id, paths, key)?;
 
-                            // Read new tree and parse it
-                            let new_tree = fs::read_to_string(
-                                paths.trees.join(format!("{}.json", new_tree_id)),
-                            )
-                            .unwrap();
-                            let new_tree: Tree = serde_json::from_str(&new_tree).ok().unwrap();
-
-                            // Recurse
-                            let sub_changes = compare_trees(&old_tree, &new_tree, &paths);
-                            all_changes.extend(sub_changes);
-                        }
+                        // Recurse
+                        let sub_changes = compare_trees(&old_tree, &new_tree, paths, key)?;
+                        all_changes.extend(sub_changes);
                     }
-                    _ => {}
                 }
                 all_changes.push(change);
             }
-            all_changes
+            Ok(all_changes)
         }
     }
 }
@@ -61,54 +55,60 @@ fn current_level_diff(old_tree: &Tree, new_tree: &Tree) -> Option<Vec<Change>> {
         let old_map: HashMap<_, _> = old_tree
             .entries
             .iter()
-            .map(|f| (f.name.clone(), (f.id.clone(), f.path.clone())))
+            .map(|f| (f.name.clone(), f))
             .collect();
         let new_map: HashMap<_, _> = new_tree
             .entries
             .iter()
-            .map(|f| (f.name.clone(), (f.id.clone(), f.path.clone())))
+            .map(|f| (f.name.clone(), f))
             .collect();
 
         // We store changes in this variable
         let mut changes = Vec::new();
 
         // iterate
-        for (name, (id, path)) in &new_map {
+        for (name, entry) in &new_map {
             match old_map.get(name) {
-                // If you cannot find it:
-                None => changes.push(Change {
+                // If you cannot find it, or it's something else now (e.g. a file became a
+                // directory), it was added:
+                Some(old) if old.kind == entry.kind => {
+                    // If you can, but its content or metadata has changed:
+                    if old.id != entry.id || old.metadata != entry.metadata {
+                        changes.push(Change {
+                            change_type: ChangeType::Modified,
+                            kind: entry.kind,
+                            name: name.clone(),
+                            path: entry.path.clone(),
+                            old_id: Some(old.id.clone()),
+                            new_id: Some(entry.id.clone()),
+                        })
+                    }
+
+                    // Otherwise, there's no change in here
+                }
+                _ => changes.push(Change {
                     change_type: ChangeType::Added,
+                    kind: entry.kind,
                     name: name.clone(),
-                    path: path.clone(),
+                    path: entry.path.clone(),
                     old_id: None,
-                    new_id: Some(id.clone()),
+                    new_id: Some(entry.id.clone()),
                 }),
-
-                // If you can, but the ID has changed:
-                Some((old_id, _)) if old_id != id => {
-                    //                    let path = path;
-                    changes.push(Change {
-                        change_type: ChangeType::Modified,
-                        name: name.clone(),
-                        path: path.clone(),
-                        old_id: Some(old_id.clone()),
-                        new_id: Some(id.clone()),
-                    })
-                }
-
-                // Otherwise, there's no change in here
-                _ => {}
             }
         }
 
-        // We also need to detect removed entries:
-        for (name, (old_id, path)) in old_map {
-            if !new_map.contains_key(&name) {
+        // We also need to detect removed entries, and the ones replaced by something else:
+        for (name, old) in old_map {
+            if new_map
+                .get(&name)
+                .is_none_or(|entry| entry.kind != old.kind)
+            {
                 changes.push(Change {
                     change_type: ChangeType::Removed,
+                    kind: old.kind,
                     name: name.clone(),
-                    path,
-                    old_id: Some(old_id.clone()),
+                    path: old.path.clone(),
+                    old_id: Some(old.id.clone()),
                     new_id: None,
                 });
             }
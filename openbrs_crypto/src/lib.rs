@@ -1,74 +1,637 @@
 use aes_gcm::{
-    Aes128Gcm, Key,
-    aead::{Aead, AeadCore, KeyInit, OsRng as Rng},
+    Aes128Gcm, Aes256Gcm,
+    aead::{
+        Aead, KeyInit,
+        generic_array::GenericArray,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
 };
+use aes_gcm_siv::Aes256GcmSiv;
 use base64::{engine::general_purpose, prelude::*};
+use chacha20poly1305::XChaCha20Poly1305;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use openbrs_error::{OpenBrsError, Result, WithPath};
 use rand::{TryRngCore, rngs::OsRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+    fmt,
     fs::{self, File},
-    io::Read,
-    path::Path,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
+use subtle::ConstantTimeEq;
+use x25519_dalek::{PublicKey, StaticSecret};
 use yescrypt::yescrypt_kdf;
 
+// Streams are sealed in chunks of this much plaintext; only the last one may be shorter
+const CHUNK_LEN: usize = 64 * 1024;
+
+// Size of the tag authenticating each chunk, the same for every cipher suite
+const TAG_LEN: usize = 16;
+
+// The part of a stream nonce holding the chunk's position, and the last-chunk flag; the rest of it
+// is random
+const COUNTER_LEN: usize = 5;
+
+const STREAM_ERROR: &str =
+    "the encrypted stream is corrupted, truncated, or not encrypted with this repository's key";
+
+// Length of X25519 keys, public and private
+const X25519_LEN: usize = 32;
+
+// Length of the salt a stream's own key is derived with, written before its nonce prefix
+const SUBKEY_SALT_LEN: usize = 32;
+
+// Length of the key object ids are hashed with
+const ID_KEY_LEN: usize = 32;
+
+// Length of Ed25519 keys, signing and verifying
+const ED25519_LEN: usize = 32;
+
+// The most recipients an archive can be sealed to; their count is stored in a byte
+const MAX_RECIPIENTS: usize = u8::MAX as usize;
+
 // To hold metadata
 // Seralize permits serializing to TOML
 // Deserialize permits deconstruting form TOML
 // Debug to print the structure
 #[derive(Serialize, Deserialize, Debug)]
 pub struct CryptoMetadata {
+    #[serde(default)]
+    cipher: Cipher, // Repositories made before it was recorded use AES-128-GCM
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    slots: Vec<KeySlot>, // Any of them unlocks the repository
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    recipients: Vec<Recipient>, // Instead of slots: each archive is sealed to all of them
+    // The key object ids are hashed with, sealed with the data key. Repositories sealed to
+    // recipients made before sealed_id_key store it as is, which lets anyone reading the
+    // repository tell whether it holds a known file; those made before it only encrypt their
+    // archives.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id_key: Option<Base64>,
+    // Sealed to recipients, the id key is kept out of the repository's reach: the identities open
+    // it sealed to each of them, and the backup hosts, which must hash with it, read it from a file
+    // of their own, checked against its digest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sealed_id_key: Option<Base64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id_key_digest: Option<Base64>,
+    // Each archive is encrypted under a key of its own, derived from the data key and a random salt
+    // it starts with, so that the short random part of the nonces only has to be unique within it.
+    // Repositories made before encrypt every archive under the data key, until it's rotated.
+    #[serde(default)]
+    subkeys: bool,
+}
+
+/// The cipher suite of an encrypted repository, chosen when it is created
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Cipher {
+    #[default]
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "aes-256-gcm-siv")]
+    Aes256GcmSiv, // Resists nonce reuse
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305, // Fast without AES instructions
+}
+
+impl Cipher {
+    fn key_len(self) -> usize {
+        match self {
+            Cipher::Aes128Gcm => 16,
+            Cipher::Aes256Gcm | Cipher::Aes256GcmSiv | Cipher::XChaCha20Poly1305 => 32,
+        }
+    }
+
+    fn nonce_len(self) -> usize {
+        match self {
+            Cipher::Aes128Gcm | Cipher::Aes256Gcm | Cipher::Aes256GcmSiv => 12,
+            Cipher::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    // The cipher, keyed
+    fn with_key(self, key: &[u8]) -> Result<Box<dyn Suite>> {
+        let invalid = |_| OpenBrsError::Crypto("the key doesn't fit the cipher suite");
+        Ok(match self {
+            Cipher::Aes128Gcm => Box::new(Aes128Gcm::new_from_slice(key).map_err(invalid)?),
+            Cipher::Aes256Gcm => Box::new(Aes256Gcm::new_from_slice(key).map_err(invalid)?),
+            Cipher::Aes256GcmSiv => Box::new(Aes256GcmSiv::new_from_slice(key).map_err(invalid)?),
+            Cipher::XChaCha20Poly1305 => {
+                Box::new(XChaCha20Poly1305::new_from_slice(key).map_err(invalid)?)
+            }
+        })
+    }
+}
+
+// What each cipher suite provides: sealing single messages, for wrapped keys, and streams, for
+// archives
+trait Suite {
+    fn encrypt(&self, nonce: &[u8], plaintext: &[u8]) -> Option<Vec<u8>>;
+    fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>;
+    fn encryptor(&self, prefix: &[u8]) -> Box<dyn StreamEncryptor>;
+    fn decryptor(&self, prefix: &[u8]) -> Box<dyn StreamDecryptor>;
+}
+
+trait StreamEncryptor {
+    fn encrypt_next(&mut self, buffer: &mut Vec<u8>) -> Option<()>;
+    fn encrypt_last(self: Box<Self>, buffer: &mut Vec<u8>) -> Option<()>;
+}
+
+trait StreamDecryptor {
+    fn decrypt_next(&mut self, buffer: &mut Vec<u8>) -> Option<()>;
+    fn decrypt_last(self: Box<Self>, buffer: &mut Vec<u8>) -> Option<()>;
+}
+
+macro_rules! suite {
+    ($($aead:ty),*) => {$(
+        impl Suite for $aead {
+            fn encrypt(&self, nonce: &[u8], plaintext: &[u8]) -> Option<Vec<u8>> {
+                Aead::encrypt(self, GenericArray::from_slice(nonce), plaintext).ok()
+            }
+
+            fn decrypt(&self, nonce: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>> {
+                Aead::decrypt(self, GenericArray::from_slice(nonce), ciphertext).ok()
+            }
+
+            fn encryptor(&self, prefix: &[u8]) -> Box<dyn StreamEncryptor> {
+                Box::new(EncryptorBE32::from_aead(self.clone(), GenericArray::from_slice(prefix)))
+            }
+
+            fn decryptor(&self, prefix: &[u8]) -> Box<dyn StreamDecryptor> {
+                Box::new(DecryptorBE32::from_aead(self.clone(), GenericArray::from_slice(prefix)))
+            }
+        }
+
+        impl StreamEncryptor for EncryptorBE32<$aead> {
+            fn encrypt_next(&mut self, buffer: &mut Vec<u8>) -> Option<()> {
+                self.encrypt_next_in_place(b"", buffer).ok()
+            }
+
+            fn encrypt_last(self: Box<Self>, buffer: &mut Vec<u8>) -> Option<()> {
+                self.encrypt_last_in_place(b"", buffer).ok()
+            }
+        }
+
+        impl StreamDecryptor for DecryptorBE32<$aead> {
+            fn decrypt_next(&mut self, buffer: &mut Vec<u8>) -> Option<()> {
+                self.decrypt_next_in_place(b"", buffer).ok()
+            }
+
+            fn decrypt_last(self: Box<Self>, buffer: &mut Vec<u8>) -> Option<()> {
+                self.decrypt_last_in_place(b"", buffer).ok()
+            }
+        }
+    )*};
+}
+
+suite!(Aes128Gcm, Aes256Gcm, Aes256GcmSiv, XChaCha20Poly1305);
+
+// One way into the repository: the data key, wrapped by the key derived from one secret
+#[derive(Serialize, Deserialize, Debug)]
+struct KeySlot {
+    label: String,
+    kind: SlotKind,
+    kdf: KdfParams,
     salt1: [u8; 16],
     salt2: [u8; 16],
     salt3: [u8; 16],
-    nonce: Option<Base64>,
     dgst: Base64,
+    wrapped_key: Base64,      // The data key, encrypted with the DPK
+    next_key: Option<Base64>, // The data key a rotation is moving the archives to
+}
+
+// A public key archives are sealed to; only its private key, which the repository never sees, can
+// open them
+#[derive(Serialize, Deserialize, Debug)]
+struct Recipient {
+    label: String,
+    public_key: Base64,
+}
+
+/// What unlocks a key slot
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotKind {
+    Password,
+    Keyfile, // The content of a file
+}
+
+/// The yescrypt parameters of a key slot. They're stored along with it, so that unlocking always
+/// derives the key the same way, whatever the machine.
+// Slots made before everything was recorded only have n, r and p; the rest is what was used then
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    #[serde(default = "KdfParams::default_flags")]
+    pub flags: u32,
+    pub n: u64, // Cost in memory and time; a power of 2
+    pub r: u32, // Block size
+    pub p: u32, // Parallelism
+    #[serde(default)]
+    pub t: u32, // Extra time cost
+    #[serde(default)]
+    pub g: u32,
+    #[serde(default = "KdfParams::default_digest_n")]
+    pub digest_n: u64, // N of the master key's digest
 }
-impl CryptoMetadata {
-    fn new(salt1: [u8; 16], salt2: [u8; 16], salt3: [u8; 16], dgst: Base64) -> Self {
+
+/// Sets of KDF parameters for common needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfPreset {
+    Interactive, // 32 MiB
+    Moderate,    // 128 MiB
+    Paranoid,    // 1 GiB
+}
+
+// The most memory calibration may pick: N = 2^20 with r = 8, 1 GiB
+const CALIBRATION_MAX_N: u64 = 1 << 20;
+
+impl KdfParams {
+    fn default_flags() -> u32 {
+        0xB6 // YESCRYPT_DEFAULTS
+    }
+
+    fn default_digest_n() -> u64 {
+        4096
+    }
+
+    fn new(n: u64, r: u32) -> Self {
         Self {
-            salt1,
-            salt2,
-            salt3,
-            dgst,
-            nonce: None,
+            flags: Self::default_flags(),
+            n,
+            r,
+            p: 1,
+            t: 0,
+            g: 0,
+            digest_n: Self::default_digest_n(),
         }
     }
-    fn set_nonce(&mut self, nonce: Base64) {
-        self.nonce = Some(nonce)
+
+    pub fn preset(preset: KdfPreset) -> Self {
+        match preset {
+            KdfPreset::Interactive => Self::new(1 << 15, 8),
+            KdfPreset::Moderate => Self::new(1 << 15, 32),
+            KdfPreset::Paranoid => Self::new(1 << 17, 64),
+        }
+    }
+
+    /// Pick the parameters deriving a key takes about `target` with on this machine: N is doubled
+    /// until a whole unlock takes at least that long, or memory use reaches 1 GiB
+    pub fn calibrate(target: Duration) -> Self {
+        let mut params = Self::new(1 << 12, 8);
+
+        loop {
+            let start = Instant::now();
+            keyder(
+                b"calibration",
+                Cipher::Aes256Gcm,
+                params,
+                [0; 16],
+                [0; 16],
+                [0; 16],
+            );
+            if start.elapsed() >= target || params.n >= CALIBRATION_MAX_N {
+                return params;
+            }
+            params.n *= 2;
+        }
+    }
+
+    // Refuse parameters yescrypt can't use, e.g. from a damaged metadata file
+    fn check(&self) -> Result<()> {
+        let valid = |n: u64| n > 1 && n.is_power_of_two();
+        if !valid(self.n) || !valid(self.digest_n) || self.r == 0 || self.p == 0 {
+            return Err(OpenBrsError::Crypto(
+                "a key slot holds invalid KDF parameters",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self::preset(KdfPreset::Moderate)
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "N={} r={} p={}", self.n, self.r, self.p)?;
+        if self.t != 0 {
+            write!(f, " t={}", self.t)?;
+        }
+        Ok(())
     }
 }
 
+/// A key slot, as listed by list_slots
+pub struct SlotInfo {
+    pub index: usize,
+    pub label: String,
+    pub kind: SlotKind,
+    pub kdf: KdfParams,
+}
+
+/// A recipient, as listed by list_recipients
+pub struct RecipientInfo {
+    pub index: usize,
+    pub label: String,
+    pub public_key: String,
+}
+
 /// Marker for Base64 encoding in JSON
 // I needed to derive these so that CryptoMetadata is valid
 #[derive(Serialize, Deserialize, Debug)]
 struct Base64(String);
 
-fn keyder(password: &[u8]) -> (Vec<u8>, CryptoMetadata) {
-    // Generate the Master Key and its salt
-    let salt1 = [0u8; 16]; // 16-byte (128-bit) salt
-    let mk = derive_b64(password, salt1, 0xB6, 32768, 32, 1, 0, 0, 16);
+impl Base64 {
+    fn encode(data: &[u8]) -> Self {
+        Self(general_purpose::STANDARD.encode(data))
+    }
+
+    fn decode(&self) -> Result<Vec<u8>> {
+        general_purpose::STANDARD
+            .decode(&self.0)
+            .map_err(|_| OpenBrsError::Crypto("the encryption metadata holds invalid Base64"))
+    }
+}
+
+/// What the objects of an encrypted repository are encrypted and decrypted with, and the key their
+/// ids are hashed with
+pub struct RepoKey {
+    sealing: Sealing,
+    id_key: Option<Vec<u8>>,
+}
 
-    // Generate the digset of the MK to store it, and its salt
-    let salt2 = [0u8; 16]; // 16-byte (128-bit) salt
-    let dgst = derive_b64(&mk, salt2, 0xB6, 4096, 32, 1, 0, 0, 16);
+// A repository with key slots has a single data key; one sealed to recipients gives each object its
+// own, which their public keys are enough to seal, and only one of their private keys opens
+enum Sealing {
+    Data(DataKey),
+    Recipients {
+        cipher: Cipher,
+        subkeys: bool,
+        public_keys: Vec<PublicKey>,
+        identity: Option<StaticSecret>,
+    },
+}
 
-    // Code the MK's digest in Base64
-    let dgst_b64 = Base64(general_purpose::STANDARD.encode(&dgst));
+impl RepoKey {
+    /// The key object ids are hashed with, so that they don't tell which content the repository
+    /// holds. None for repositories made before trees and commits were encrypted, which are left
+    /// in the clear.
+    pub fn id_key(&self) -> Option<&[u8]> {
+        self.id_key.as_deref()
+    }
 
-    // Generate the DPK to use it to encrypt
-    let salt3 = [0u8; 16]; // 16-byte (128-bit) salt
-    let dpk = derive_b64(&mk, salt3, 0xB6, 32768, 32, 1, 0, 0, 16);
+    /// Whether objects can be decrypted; a host sealing to recipients can't read back what it wrote
+    pub fn can_decrypt(&self) -> bool {
+        match &self.sealing {
+            Sealing::Data(_) => true,
+            Sealing::Recipients { identity, .. } => identity.is_some(),
+        }
+    }
+}
+
+// The random data key archives are encrypted with, and its cipher suite. Each key slot stores it
+// wrapped by the key derived from its secret, so that changing a secret doesn't touch the archives.
+struct DataKey {
+    cipher: Cipher,
+    key: Vec<u8>,
+    subkeys: bool, // Whether each stream derives a key of its own from it
+}
+
+impl DataKey {
+    // A fresh data key
+    fn generate(cipher: Cipher) -> Result<Self> {
+        Ok(Self {
+            cipher,
+            key: random_vec(cipher.key_len())?,
+            subkeys: true,
+        })
+    }
+
+    // The key of the stream starting with `salt`
+    fn subkey(&self, salt: &[u8]) -> Result<Vec<u8>> {
+        let mut key = vec![0u8; self.cipher.key_len()];
+        Hkdf::<Sha256>::new(Some(salt), &self.key)
+            .expand(b"openbrs stream", &mut key)
+            .map_err(|_| OpenBrsError::Crypto("the stream key derivation failed"))?;
+
+        Ok(key)
+    }
+}
+
+// Length of a data key sealed to one recipient
+fn stanza_len(cipher: Cipher) -> usize {
+    cipher.nonce_len() + cipher.key_len() + TAG_LEN
+}
+
+// The key a data key is sealed with for one recipient, from the X25519 secret the ephemeral key
+// shares with it. Both public keys are mixed in, binding the stanza to this exchange.
+fn recipient_wrap_key(
+    cipher: Cipher,
+    shared: &[u8; X25519_LEN],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<Vec<u8>> {
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = vec![0u8; cipher.key_len()];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(b"openbrs recipient", &mut key)
+        .map_err(|_| OpenBrsError::Crypto("the recipient key derivation failed"))?;
+
+    Ok(key)
+}
+
+// Pick a fresh data key for an archive, and seal it to every recipient. The header is an ephemeral
+// public key, the number of recipients, then the data key sealed to each of them.
+fn seal_to_recipients(
+    cipher: Cipher,
+    subkeys: bool,
+    public_keys: &[PublicKey],
+) -> Result<(Vec<u8>, DataKey)> {
+    let key = DataKey {
+        subkeys,
+        ..DataKey::generate(cipher)?
+    };
+    let ephemeral = StaticSecret::from(random::<X25519_LEN>()?);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+
+    let mut header = ephemeral_public.as_bytes().to_vec();
+    header.push(public_keys.len() as u8);
+    for recipient in public_keys {
+        let shared = ephemeral.diffie_hellman(recipient);
+        if !shared.was_contributory() {
+            return Err(OpenBrsError::Crypto("a recipient's public key is invalid"));
+        }
+
+        let wrap_key = recipient_wrap_key(cipher, shared.as_bytes(), &ephemeral_public, recipient)?;
+        header.extend_from_slice(&seal(cipher, &wrap_key, &key.key)?);
+    }
+
+    Ok((header, key))
+}
+
+// Read the header seal_to_recipients wrote, and open the data key sealed to this private key
+fn open_from_recipients(
+    reader: &mut impl Read,
+    cipher: Cipher,
+    subkeys: bool,
+    secret: &StaticSecret,
+) -> io::Result<DataKey> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    let mut ephemeral = [0u8; X25519_LEN];
+    reader.read_exact(&mut ephemeral)?;
+    let ephemeral = PublicKey::from(ephemeral);
+    let mut count = [0u8; 1];
+    reader.read_exact(&mut count)?;
+    let mut stanzas = vec![0u8; count[0] as usize * stanza_len(cipher)];
+    reader.read_exact(&mut stanzas)?;
+
+    let shared = secret.diffie_hellman(&ephemeral);
+    if !shared.was_contributory() {
+        return Err(invalid(STREAM_ERROR));
+    }
+    let wrap_key = recipient_wrap_key(
+        cipher,
+        shared.as_bytes(),
+        &ephemeral,
+        &PublicKey::from(secret),
+    )
+    .map_err(io::Error::other)?;
+
+    // The stanzas don't say whom they're for, so each one is tried
+    for stanza in stanzas.chunks(stanza_len(cipher)) {
+        if let Some(key) = open_sealed(cipher, &wrap_key, stanza).map_err(io::Error::other)? {
+            return Ok(DataKey {
+                cipher,
+                key,
+                subkeys,
+            });
+        }
+    }
+
+    Err(invalid("the archive isn't sealed to this identity"))
+}
+
+// An X25519 or Ed25519 key, public or private, from its Base64 form
+fn decode_key(text: &str) -> Option<[u8; 32]> {
+    Base64(text.trim().to_string())
+        .decode()
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+}
+
+/// An Ed25519 key commits are signed with, so that a history can't be forged by whoever can write
+/// to the repository
+pub struct CommitSigner(SigningKey);
+
+impl CommitSigner {
+    /// Parse a signing key, as generate_signing_key makes it
+    pub fn new(signing_key: &str) -> Result<Self> {
+        let seed: [u8; ED25519_LEN] =
+            decode_key(signing_key).ok_or(OpenBrsError::Crypto("invalid signing key"))?;
+        Ok(Self(SigningKey::from_bytes(&seed)))
+    }
+
+    /// The public key verifying this key's signatures, in Base64
+    pub fn public_key(&self) -> String {
+        Base64::encode(self.0.verifying_key().as_bytes()).0
+    }
+
+    /// Sign a message; the signature is returned in Base64
+    pub fn sign(&self, message: &[u8]) -> String {
+        Base64::encode(&self.0.sign(message).to_bytes()).0
+    }
+}
+
+/// Make an Ed25519 key to sign commits with; returns it, to keep on the backup host, and its
+/// public key, to list in the repository's config
+pub fn generate_signing_key() -> Result<(String, String)> {
+    let signer = CommitSigner(SigningKey::from_bytes(&random::<ED25519_LEN>()?));
+
+    Ok((Base64::encode(signer.0.as_bytes()).0, signer.public_key()))
+}
+
+/// Check that a public key, in Base64, is a valid Ed25519 one
+pub fn check_verifying_key(public_key: &str) -> Result<()> {
+    decode_key(public_key)
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .map(|_| ())
+        .ok_or(OpenBrsError::Crypto("invalid public key"))
+}
+
+/// Whether `signature`, in Base64, is a valid signature of `message` by `public_key`. Keys and
+/// signatures that don't even parse don't verify.
+pub fn verify_signature(public_key: &str, message: &[u8], signature: &str) -> bool {
+    let Some(key) = decode_key(public_key).and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+    else {
+        return false;
+    };
+    let Some(signature) = Base64(signature.to_string())
+        .decode()
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+
+    // Strict verification refuses the malleable and weak-key edge cases
+    key.verify_strict(message, &signature).is_ok()
+}
+
+// Parse a public key, as generate_identity returns it
+fn parse_public_key(public_key: &str) -> Result<PublicKey> {
+    decode_key(public_key)
+        .map(PublicKey::from)
+        .ok_or(OpenBrsError::Crypto("invalid public key"))
+}
+
+// Returns the DPK, as long as the cipher's keys, and the digest of the master key
+fn keyder(
+    password: &[u8],
+    cipher: Cipher,
+    kdf: KdfParams,
+    salt1: [u8; 16],
+    salt2: [u8; 16],
+    salt3: [u8; 16],
+) -> (Vec<u8>, Vec<u8>) {
+    let KdfParams {
+        flags,
+        n,
+        r,
+        p,
+        t,
+        g,
+        digest_n,
+    } = kdf;
+    let key_len = cipher.key_len();
+
+    // Generate the Master Key from its salt
+    let mk = derive_b64(password, salt1, flags, n, r, p, t, g, key_len);
+
+    // Generate the digset of the MK to store it
+    let dgst = derive_b64(&mk, salt2, flags, digest_n, r, p, t, g, 16);
 
-    // Save the metadata to file
-    let metadata = CryptoMetadata::new(salt1, salt2, salt3, dgst_b64);
+    // Generate the DPK to use it to encrypt
+    let dpk = derive_b64(&mk, salt3, flags, n, r, p, t, g, key_len);
 
-    (dpk, metadata)
+    (dpk, dgst)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn derive_b64(
     password: &[u8],
-    mut salt: [u8; 16],
+    salt: [u8; 16],
     flags: u32,
     n: u64,
     r: u32,
@@ -77,56 +640,1064 @@ fn derive_b64(
     g: u32,
     dstlen: usize,
 ) -> Vec<u8> {
-    // fill with CSPRNG; the beginning of the line is to contain the Err that may generate.
-    let _ = OsRng.try_fill_bytes(&mut salt);
+    // Derive, then return
+    yescrypt_kdf(password, &salt, flags, n, r, p, t, g, dstlen)
+}
 
-    // Derive
-    let key = yescrypt_kdf(&password, &salt, flags, n, r, p, t, g, dstlen);
+// Random bytes, for salts and keys
+fn random<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
 
-    // Return
-    key
+    // fill with CSPRNG
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .map_err(|_| OpenBrsError::Crypto("the system's random generator failed"))?;
+
+    Ok(bytes)
 }
 
-pub fn encrypt_archive(archive_path: &Path, password: &[u8]) -> Vec<u8> {
-    // Get the DPK, and the metadata to write it off
-    let (dpk, mut metadata) = keyder(password);
+// Random bytes, of a length only known at runtime
+fn random_vec(len: usize) -> Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
 
-    // Turn our key to the format that the function accepts
-    let key = Key::<Aes128Gcm>::from_slice(&dpk);
+    OsRng
+        .try_fill_bytes(&mut bytes)
+        .map_err(|_| OpenBrsError::Crypto("the system's random generator failed"))?;
 
-    // Set the cipher function
-    let cipher = Aes128Gcm::new(&key);
+    Ok(bytes)
+}
 
-    // Generate the nonce
-    let nonce = Aes128Gcm::generate_nonce(&mut Rng); // 96-bits; unique per message
+// Make a key slot for a secret, with fresh salts, wrapping the data keys
+fn new_slot(
+    secret: &[u8],
+    kind: SlotKind,
+    label: &str,
+    kdf: KdfParams,
+    key: &DataKey,
+    next_key: Option<&DataKey>,
+) -> Result<KeySlot> {
+    let (salt1, salt2, salt3) = (random()?, random()?, random()?);
+    let (dpk, dgst) = keyder(secret, key.cipher, kdf, salt1, salt2, salt3);
 
-    // Add the nonce to the metadata, in base64 encoding
-    metadata.set_nonce(Base64(general_purpose::STANDARD.encode(&nonce)));
+    // The MK's digest is what the secret is checked against
+    Ok(KeySlot {
+        label: label.to_string(),
+        kind,
+        kdf,
+        salt1,
+        salt2,
+        salt3,
+        dgst: Base64::encode(&dgst),
+        wrapped_key: Base64::encode(&seal(key.cipher, &dpk, &key.key)?),
+        next_key: match next_key {
+            Some(next_key) => Some(Base64::encode(&seal(key.cipher, &dpk, &next_key.key)?)),
+            None => None,
+        },
+    })
+}
+
+// The DPK of a slot, if the secret is its own
+fn check_slot(secret: &[u8], cipher: Cipher, slot: &KeySlot) -> Result<Option<Vec<u8>>> {
+    slot.kdf.check()?;
+    let (dpk, dgst) = keyder(secret, cipher, slot.kdf, slot.salt1, slot.salt2, slot.salt3);
 
+    // Compare in constant time, not to leak how much of the digest matched
+    match bool::from(dgst.ct_eq(&slot.dgst.decode()?)) {
+        true => Ok(Some(dpk)),
+        false => Ok(None),
+    }
+}
+
+// Find the slot the secret unlocks, and return it along with its DPK
+fn open_slot(secret: &[u8], metadata: &CryptoMetadata) -> Result<(usize, Vec<u8>)> {
+    if metadata.slots.is_empty() {
+        return Err(OpenBrsError::SealedToRecipients);
+    }
+
+    for (index, slot) in metadata.slots.iter().enumerate() {
+        if let Some(dpk) = check_slot(secret, metadata.cipher, slot)? {
+            return Ok((index, dpk));
+        }
+    }
+
+    Err(OpenBrsError::WrongPassword)
+}
+
+// Unwrap the data keys held by a slot: the current one, and the one a rotation is moving to, which
+// always derives stream keys
+fn unwrap_keys(
+    metadata: &CryptoMetadata,
+    dpk: &[u8],
+    slot: &KeySlot,
+) -> Result<(DataKey, Option<DataKey>)> {
+    let key = unwrap_key(metadata.cipher, metadata.subkeys, dpk, &slot.wrapped_key)?;
+    let next_key = match &slot.next_key {
+        Some(wrapped) => Some(unwrap_key(metadata.cipher, true, dpk, wrapped)?),
+        None => None,
+    };
+
+    Ok((key, next_key))
+}
+
+fn unwrap_key(cipher: Cipher, subkeys: bool, dpk: &[u8], wrapped: &Base64) -> Result<DataKey> {
+    let key = open_sealed(cipher, dpk, &wrapped.decode()?)?
+        .ok_or(OpenBrsError::Crypto("the wrapped data key is corrupted"))?;
+    Ok(DataKey {
+        cipher,
+        key,
+        subkeys,
+    })
+}
+
+fn read_metadata(metadata_path: &Path) -> Result<CryptoMetadata> {
+    let toml_string = fs::read_to_string(metadata_path).at(metadata_path)?;
+    toml::from_str(&toml_string).at(metadata_path)
+}
+
+// Losing the metadata would lose every archive, so it's never left half written
+fn write_metadata(metadata: &CryptoMetadata, metadata_path: &Path) -> Result<()> {
     // Turning the string to TOML format
-    let toml_string = toml::to_string(&metadata).unwrap();
+    let toml_string = toml::to_string(metadata).at(metadata_path)?;
 
-    // Write off the metadata
-    fs::write("test/.metadata", toml_string).unwrap();
+    write_atomically(metadata_path, toml_string.as_bytes())
+}
 
-    // Read the file before encrypting it
-    let mut plaintext = Vec::new();
-    let mut file = File::open(archive_path).unwrap();
-    file.read_to_end(&mut plaintext).unwrap();
+// Write to a temporary file, flush it to disk, then give it its name
+fn write_atomically(path: &Path, content: &[u8]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
 
-    // Encipher the archive
-    let ciphertext = cipher.encrypt(&nonce, plaintext.as_ref()).unwrap();
+    fs::write(&tmp, content).at(&tmp)?;
+    File::open(&tmp).and_then(|file| file.sync_all()).at(&tmp)?;
+    fs::rename(&tmp, path).at(path)
+}
 
-    // write the cipher
-    fs::write(
-        &archive_path.parent().unwrap().join(format!(
-            "{}.enc",
-            archive_path.file_name().unwrap().to_str().unwrap()
+/// Set up encryption for a repository: pick a random data key for the cipher suite, and write it to
+/// `metadata_path` wrapped in a first key slot, unlocked by `secret` through `kdf`
+pub fn init_encryption(
+    secret: &[u8],
+    kind: SlotKind,
+    label: &str,
+    kdf: KdfParams,
+    cipher: Cipher,
+    metadata_path: &Path,
+) -> Result<RepoKey> {
+    let key = DataKey::generate(cipher)?;
+    let id_key = random_vec(ID_KEY_LEN)?;
+
+    let metadata = CryptoMetadata {
+        cipher,
+        slots: vec![new_slot(secret, kind, label, kdf, &key, None)?],
+        recipients: Vec::new(),
+        id_key: Some(Base64::encode(&seal(cipher, &key.key, &id_key)?)),
+        sealed_id_key: None,
+        id_key_digest: None,
+        subkeys: true,
+    };
+    write_metadata(&metadata, metadata_path)?;
+
+    Ok(RepoKey {
+        sealing: Sealing::Data(key),
+        id_key: Some(id_key),
+    })
+}
+
+/// Set up encryption for a repository sealed to recipients: each archive will get its own data
+/// key, sealed to every public key, so that backing up takes no secret and only the matching
+/// private keys can restore. Returns the id key, in Base64, for the backup hosts to keep; the
+/// repository only holds it sealed to the recipients.
+pub fn init_recipients(
+    public_keys: &[String],
+    label: &str,
+    cipher: Cipher,
+    metadata_path: &Path,
+) -> Result<String> {
+    let mut recipients = Vec::new();
+    for public_key in public_keys {
+        recipients.push(Recipient {
+            label: label.to_string(),
+            public_key: Base64::encode(parse_public_key(public_key)?.as_bytes()),
+        });
+    }
+    if recipients.len() > MAX_RECIPIENTS {
+        return Err(OpenBrsError::Crypto(
+            "an archive can't be sealed to that many recipients",
+        ));
+    }
+
+    let id_key = random_vec(ID_KEY_LEN)?;
+    let mut metadata = CryptoMetadata {
+        cipher,
+        slots: Vec::new(),
+        recipients,
+        id_key: None,
+        sealed_id_key: None,
+        id_key_digest: Some(id_key_digest(&id_key)),
+        subkeys: true,
+    };
+    metadata.sealed_id_key = Some(seal_id_key(&metadata, &id_key)?);
+    write_metadata(&metadata, metadata_path)?;
+
+    Ok(Base64::encode(&id_key).0)
+}
+
+// Seal the id key to every recipient, the same way as an archive's data key
+fn seal_id_key(metadata: &CryptoMetadata, id_key: &[u8]) -> Result<Base64> {
+    let (mut sealed, key) = seal_to_recipients(metadata.cipher, false, &public_keys(metadata)?)?;
+    sealed.extend_from_slice(&seal(metadata.cipher, &key.key, id_key)?);
+
+    Ok(Base64::encode(&sealed))
+}
+
+fn open_id_key(sealed: &Base64, cipher: Cipher, identity: &StaticSecret) -> Result<Vec<u8>> {
+    let sealed = sealed.decode()?;
+    let mut reader = sealed.as_slice();
+    let key = open_from_recipients(&mut reader, cipher, false, identity)
+        .map_err(|_| OpenBrsError::Crypto("the id key isn't sealed to this identity"))?;
+
+    open_sealed(cipher, &key.key, reader)?
+        .ok_or(OpenBrsError::Crypto("the sealed id key is corrupted"))
+}
+
+// What a backup host's copy of the id key is checked against; the key is random, so its digest
+// tells nothing about it
+fn id_key_digest(id_key: &[u8]) -> Base64 {
+    let mut hasher = Sha256::new();
+    hasher.update(b"openbrs id key");
+    hasher.update(id_key);
+    Base64::encode(&hasher.finalize())
+}
+
+// The id key a backup host keeps, in Base64 as init_recipients returned it
+fn host_id_key(id_key: &str, metadata: &CryptoMetadata) -> Result<Vec<u8>> {
+    let id_key = Base64(id_key.trim().to_string()).decode()?;
+
+    match &metadata.id_key_digest {
+        Some(digest) if id_key_digest(&id_key).0 == digest.0 => Ok(id_key),
+        _ => Err(OpenBrsError::Crypto(
+            "the id key file isn't the one of this repository",
         )),
-        &ciphertext,
-    )
-    .unwrap();
+    }
+}
+
+/// Make an X25519 identity; returns its private key, to keep away from the backup hosts, and its
+/// public key, to seal repositories to
+pub fn generate_identity() -> Result<(String, String)> {
+    let secret = StaticSecret::from(random::<X25519_LEN>()?);
+    let public_key = PublicKey::from(&secret);
 
-    // Return file content
-    plaintext
+    Ok((
+        Base64::encode(secret.as_bytes()).0,
+        Base64::encode(public_key.as_bytes()).0,
+    ))
+}
+
+/// Whether the trees and commits of an encrypted repository are sealed too; older repositories only
+/// encrypt their archives
+pub fn seals_objects(metadata_path: &Path) -> Result<bool> {
+    let metadata = read_metadata(metadata_path)?;
+    Ok(metadata.id_key.is_some() || metadata.sealed_id_key.is_some())
+}
+
+/// Whether the repository is sealed to recipients rather than unlocked by key slots
+pub fn has_recipients(metadata_path: &Path) -> Result<bool> {
+    Ok(!read_metadata(metadata_path)?.recipients.is_empty())
+}
+
+/// The key new objects of a repository sealed to recipients are encrypted with; no secret is
+/// needed, but nothing can be decrypted with it. `read_id_key` gives the id key init_recipients
+/// returned, which is only asked for if the repository doesn't store it as is.
+pub fn recipients_key(
+    metadata_path: &Path,
+    read_id_key: impl FnOnce() -> Result<String>,
+) -> Result<RepoKey> {
+    let metadata = read_metadata(metadata_path)?;
+    let id_key = match (&metadata.id_key, &metadata.sealed_id_key) {
+        (Some(id_key), _) => Some(id_key.decode()?),
+        (None, Some(_)) => Some(host_id_key(&read_id_key()?, &metadata)?),
+        (None, None) => None,
+    };
+
+    recipients_repo_key(&metadata, None, id_key)
+}
+
+/// The key objects of a repository sealed to recipients are decrypted with, from the private key
+/// generate_identity made. An identity which isn't a recipient fails on the first object.
+pub fn unlock_identity(identity: &str, metadata_path: &Path) -> Result<RepoKey> {
+    let secret = decode_key(identity).ok_or(OpenBrsError::Crypto("invalid identity"))?;
+    let secret = StaticSecret::from(secret);
+
+    let metadata = read_metadata(metadata_path)?;
+    let id_key = match (&metadata.id_key, &metadata.sealed_id_key) {
+        (Some(id_key), _) => Some(id_key.decode()?),
+        (None, Some(sealed)) => Some(open_id_key(sealed, metadata.cipher, &secret)?),
+        (None, None) => None,
+    };
+
+    recipients_repo_key(&metadata, Some(secret), id_key)
+}
+
+fn recipients_repo_key(
+    metadata: &CryptoMetadata,
+    identity: Option<StaticSecret>,
+    id_key: Option<Vec<u8>>,
+) -> Result<RepoKey> {
+    Ok(RepoKey {
+        sealing: Sealing::Recipients {
+            cipher: metadata.cipher,
+            subkeys: metadata.subkeys,
+            public_keys: public_keys(metadata)?,
+            identity,
+        },
+        id_key,
+    })
+}
+
+fn public_keys(metadata: &CryptoMetadata) -> Result<Vec<PublicKey>> {
+    metadata
+        .recipients
+        .iter()
+        .map(|recipient| parse_public_key(&recipient.public_key.0))
+        .collect()
+}
+
+/// Seal new archives to one more public key; archives made before are left as they are. The id
+/// key is sealed to it too, which takes the one `read_id_key` gives, as for recipients_key.
+/// Returns the recipient's index.
+pub fn add_recipient(
+    public_key: &str,
+    label: &str,
+    metadata_path: &Path,
+    read_id_key: impl FnOnce() -> Result<String>,
+) -> Result<usize> {
+    let mut metadata = read_metadata(metadata_path)?;
+    if metadata.recipients.is_empty() {
+        return Err(OpenBrsError::Crypto(
+            "the repository is unlocked by key slots, not sealed to recipients",
+        ));
+    }
+    if metadata.recipients.len() == MAX_RECIPIENTS {
+        return Err(OpenBrsError::Crypto(
+            "an archive can't be sealed to that many recipients",
+        ));
+    }
+
+    metadata.recipients.push(Recipient {
+        label: label.to_string(),
+        public_key: Base64::encode(parse_public_key(public_key)?.as_bytes()),
+    });
+    if metadata.sealed_id_key.is_some() {
+        let id_key = host_id_key(&read_id_key()?, &metadata)?;
+        metadata.sealed_id_key = Some(seal_id_key(&metadata, &id_key)?);
+    }
+    write_metadata(&metadata, metadata_path)?;
+
+    Ok(metadata.recipients.len() - 1)
+}
+
+/// List the recipients of a repository sealed to them
+pub fn list_recipients(metadata_path: &Path) -> Result<Vec<RecipientInfo>> {
+    let metadata = read_metadata(metadata_path)?;
+
+    Ok(metadata
+        .recipients
+        .into_iter()
+        .enumerate()
+        .map(|(index, recipient)| RecipientInfo {
+            index,
+            label: recipient.label,
+            public_key: recipient.public_key.0,
+        })
+        .collect())
+}
+
+/// Rename a recipient
+pub fn label_recipient(index: usize, label: &str, metadata_path: &Path) -> Result<()> {
+    let mut metadata = read_metadata(metadata_path)?;
+
+    let recipient = metadata
+        .recipients
+        .get_mut(index)
+        .ok_or(OpenBrsError::UnknownKeySlot(index))?;
+    recipient.label = label.to_string();
+
+    write_metadata(&metadata, metadata_path)
+}
+
+/// Stop sealing new archives to a recipient; the archives already sealed to it stay readable with
+/// its private key. The last recipient is never removed.
+pub fn remove_recipient(index: usize, metadata_path: &Path) -> Result<()> {
+    let mut metadata = read_metadata(metadata_path)?;
+
+    if index >= metadata.recipients.len() {
+        return Err(OpenBrsError::UnknownKeySlot(index));
+    }
+    if metadata.recipients.len() == 1 {
+        return Err(OpenBrsError::Crypto(
+            "the last recipient can't be removed, new archives would be sealed to nobody",
+        ));
+    }
+
+    metadata.recipients.remove(index);
+    write_metadata(&metadata, metadata_path)
+}
+
+/// Unwrap the data key of an encrypted repository with the secret of any of its key slots. The
+/// secret is checked against each slot's digest of the master key, so that a wrong one fails here
+/// rather than on the first archive to decrypt.
+pub fn unlock(secret: &[u8], metadata_path: &Path) -> Result<RepoKey> {
+    let metadata = read_metadata(metadata_path)?;
+    let (index, dpk) = open_slot(secret, &metadata)?;
+    let (key, next_key) = unwrap_keys(&metadata, &dpk, &metadata.slots[index])?;
+
+    // Halfway through a rotation, archives are under either key
+    if next_key.is_some() {
+        return Err(OpenBrsError::RotationInProgress);
+    }
+
+    let id_key = match &metadata.id_key {
+        Some(sealed) => Some(unseal_id_key(&key, sealed)?),
+        None => None,
+    };
+
+    Ok(RepoKey {
+        sealing: Sealing::Data(key),
+        id_key,
+    })
+}
+
+fn unseal_id_key(key: &DataKey, sealed: &Base64) -> Result<Vec<u8>> {
+    open_sealed(key.cipher, &key.key, &sealed.decode()?)?
+        .ok_or(OpenBrsError::Crypto("the sealed id key is corrupted"))
+}
+
+/// Replace the secret of the key slot `old_secret` unlocks with a new password, keeping its KDF
+/// parameters. Only the data key is wrapped again, the archives are left as they are.
+pub fn change_password(old_secret: &[u8], new_password: &[u8], metadata_path: &Path) -> Result<()> {
+    let mut metadata = read_metadata(metadata_path)?;
+    let (index, dpk) = open_slot(old_secret, &metadata)?;
+
+    // A pending rotation carries on under the new password
+    let (key, next_key) = unwrap_keys(&metadata, &dpk, &metadata.slots[index])?;
+    let (label, kdf) = (
+        metadata.slots[index].label.clone(),
+        metadata.slots[index].kdf,
+    );
+    metadata.slots[index] = new_slot(
+        new_password,
+        SlotKind::Password,
+        &label,
+        kdf,
+        &key,
+        next_key.as_ref(),
+    )?;
+
+    write_metadata(&metadata, metadata_path)
+}
+
+/// Add a key slot unlocked by `new_secret` through `kdf`, given the secret of an existing one.
+/// Returns the new slot's index.
+pub fn add_slot(
+    secret: &[u8],
+    new_secret: &[u8],
+    kind: SlotKind,
+    label: &str,
+    kdf: KdfParams,
+    metadata_path: &Path,
+) -> Result<usize> {
+    let mut metadata = read_metadata(metadata_path)?;
+    let (index, dpk) = open_slot(secret, &metadata)?;
+    let (key, next_key) = unwrap_keys(&metadata, &dpk, &metadata.slots[index])?;
+
+    metadata.slots.push(new_slot(
+        new_secret,
+        kind,
+        label,
+        kdf,
+        &key,
+        next_key.as_ref(),
+    )?);
+    write_metadata(&metadata, metadata_path)?;
+
+    Ok(metadata.slots.len() - 1)
+}
+
+/// List the key slots of an encrypted repository
+pub fn list_slots(metadata_path: &Path) -> Result<Vec<SlotInfo>> {
+    let metadata = read_metadata(metadata_path)?;
+
+    Ok(metadata
+        .slots
+        .iter()
+        .enumerate()
+        .map(|(index, slot)| SlotInfo {
+            index,
+            label: slot.label.clone(),
+            kind: slot.kind,
+            kdf: slot.kdf,
+        })
+        .collect())
+}
+
+/// Rename a key slot
+pub fn label_slot(index: usize, label: &str, metadata_path: &Path) -> Result<()> {
+    let mut metadata = read_metadata(metadata_path)?;
+
+    let slot = metadata
+        .slots
+        .get_mut(index)
+        .ok_or(OpenBrsError::UnknownKeySlot(index))?;
+    slot.label = label.to_string();
+
+    write_metadata(&metadata, metadata_path)
+}
+
+/// Remove a key slot, given the secret of any slot. The last slot is never removed, as nothing
+/// could unlock the repository anymore.
+pub fn remove_slot(secret: &[u8], index: usize, metadata_path: &Path) -> Result<()> {
+    let mut metadata = read_metadata(metadata_path)?;
+    open_slot(secret, &metadata)?;
+
+    if index >= metadata.slots.len() {
+        return Err(OpenBrsError::UnknownKeySlot(index));
+    }
+    if metadata.slots.len() == 1 {
+        return Err(OpenBrsError::LastKeySlot);
+    }
+
+    metadata.slots.remove(index);
+    write_metadata(&metadata, metadata_path)
+}
+
+/// Move every encrypted object found in `dirs`, and those `packed` re-encrypts through the
+/// rotation it's given, to a new data key. Starting a rotation wraps the new key in every slot, so
+/// `others` is asked for the secret of each slot but the one `secret` unlocks; resuming one only
+/// takes `secret`. The new key is recorded before any object is touched, and each object is
+/// replaced as a whole, so an interrupted rotation is picked up where it stopped by running it
+/// again. Returns how many objects were re-encrypted.
+pub fn rotate_key(
+    secret: &[u8],
+    metadata_path: &Path,
+    dirs: &[&Path],
+    mut others: impl FnMut(&SlotInfo) -> Result<Vec<u8>>,
+    packed: impl FnOnce(&Rotation) -> Result<usize>,
+) -> Result<usize> {
+    let mut metadata = read_metadata(metadata_path)?;
+    let (index, dpk) = open_slot(secret, &metadata)?;
+    let (old_key, next_key) = unwrap_keys(&metadata, &dpk, &metadata.slots[index])?;
+
+    // Start a rotation, or resume the one that was interrupted
+    let new_key = match next_key {
+        Some(next_key) => next_key,
+        None => {
+            let new_key = DataKey::generate(metadata.cipher)?;
+
+            // Each slot must wrap the new key, so each slot's secret is needed
+            let mut wrapped = Vec::new();
+            for (other, slot) in metadata.slots.iter().enumerate() {
+                let slot_dpk = match other == index {
+                    true => dpk.clone(),
+                    false => {
+                        let info = SlotInfo {
+                            index: other,
+                            label: slot.label.clone(),
+                            kind: slot.kind,
+                            kdf: slot.kdf,
+                        };
+                        check_slot(&others(&info)?, metadata.cipher, slot)?
+                            .ok_or(OpenBrsError::WrongPassword)?
+                    }
+                };
+                wrapped.push(Base64::encode(&seal(
+                    metadata.cipher,
+                    &slot_dpk,
+                    &new_key.key,
+                )?));
+            }
+            for (slot, wrapped) in metadata.slots.iter_mut().zip(wrapped) {
+                slot.next_key = Some(wrapped);
+            }
+            write_metadata(&metadata, metadata_path)?;
+
+            new_key
+        }
+    };
+
+    let mut count = 0;
+    for dir in dirs {
+        for entry in fs::read_dir(dir).at(dir)? {
+            let path = entry.at(dir)?.path();
+            if path.extension().is_none_or(|extension| extension != "enc") {
+                continue;
+            }
+
+            // Already moved before the interruption
+            if encrypted_with(File::open(&path).at(&path)?, &new_key) {
+                continue;
+            }
+
+            reencrypt(&path, &old_key, &new_key)?;
+            count += 1;
+        }
+    }
+    let rotation = Rotation { old_key, new_key };
+    count += packed(&rotation)?;
+    let Rotation { old_key, new_key } = rotation;
+
+    // Every object is under the new key, which can now replace the old one; the id key stays the
+    // same, so that the objects keep their names. Objects of older repositories now all have keys
+    // of their own.
+    metadata.subkeys = true;
+    for slot in &mut metadata.slots {
+        if let Some(next_key) = slot.next_key.take() {
+            slot.wrapped_key = next_key;
+        }
+    }
+    if let Some(sealed) = &metadata.id_key {
+        let id_key = unseal_id_key(&old_key, sealed)?;
+        metadata.id_key = Some(Base64::encode(&seal(
+            new_key.cipher,
+            &new_key.key,
+            &id_key,
+        )?));
+    }
+    write_metadata(&metadata, metadata_path)?;
+
+    Ok(count)
+}
+
+// Encrypt under a fresh nonce, which is written first
+fn seal(cipher: Cipher, key: &[u8], plaintext: &[u8]) -> Result<Vec<u8>> {
+    // Generate the nonce, unique per message
+    let mut content = random_vec(cipher.nonce_len())?;
+
+    // Encipher, then write the cipher after the nonce
+    let ciphertext = cipher
+        .with_key(key)?
+        .encrypt(&content, plaintext)
+        .ok_or(OpenBrsError::Crypto("encryption failed"))?;
+    content.extend_from_slice(&ciphertext);
+
+    Ok(content)
+}
+
+// Decrypt what seal wrote; None if it was sealed with another key, or tampered with
+fn open_sealed(cipher: Cipher, key: &[u8], content: &[u8]) -> Result<Option<Vec<u8>>> {
+    if content.len() < cipher.nonce_len() {
+        return Ok(None);
+    }
+
+    // The nonce comes first
+    let (nonce, ciphertext) = content.split_at(cipher.nonce_len());
+
+    Ok(cipher.with_key(key)?.decrypt(nonce, ciphertext))
+}
+
+/// Encrypts everything written to it as a stream of chunks, each sealed on its own (the STREAM
+/// construction): every chunk's nonce holds its position and whether it is the last one, so that
+/// truncated, reordered or spliced streams fail to decrypt. Memory use is bounded by the chunk size.
+/// `finish` must be called to seal the last chunk.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    encryptor: Option<Box<dyn StreamEncryptor>>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    /// Start a stream under a key of its own, derived from the data key and a random salt, and a
+    /// random nonce prefix, both written first. Sealing to recipients, the stream gets its own data
+    /// key too, which the header before it holds sealed to each of them.
+    pub fn new(mut inner: W, key: &RepoKey) -> io::Result<Self> {
+        match &key.sealing {
+            Sealing::Data(key) => Self::with_data_key(inner, key),
+            Sealing::Recipients {
+                cipher,
+                subkeys,
+                public_keys,
+                ..
+            } => {
+                let (header, key) =
+                    seal_to_recipients(*cipher, *subkeys, public_keys).map_err(io::Error::other)?;
+                inner.write_all(&header)?;
+                Self::with_data_key(inner, &key)
+            }
+        }
+    }
+
+    fn with_data_key(mut inner: W, key: &DataKey) -> io::Result<Self> {
+        // The stream's own key, from the salt written first
+        let stream_key = match key.subkeys {
+            true => {
+                let salt = random_vec(SUBKEY_SALT_LEN).map_err(io::Error::other)?;
+                inner.write_all(&salt)?;
+                key.subkey(&salt).map_err(io::Error::other)?
+            }
+            false => key.key.clone(),
+        };
+
+        let prefix = random_vec(key.cipher.nonce_len() - COUNTER_LEN).map_err(io::Error::other)?;
+        inner.write_all(&prefix)?;
+
+        let cipher = key.cipher.with_key(&stream_key).map_err(io::Error::other)?;
+        Ok(Self {
+            inner,
+            encryptor: Some(cipher.encryptor(&prefix)),
+            buffer: Vec::with_capacity(CHUNK_LEN + TAG_LEN),
+        })
+    }
+
+    /// Seal the last chunk, which may be empty, and return the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(encryptor) = self.encryptor.take() {
+            encryptor
+                .encrypt_last(&mut self.buffer)
+                .ok_or_else(|| io::Error::other("encryption failed"))?;
+            self.inner.write_all(&self.buffer)?;
+        }
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let encryptor = self
+            .encryptor
+            .as_mut()
+            .ok_or_else(|| io::Error::other("the stream is already finished"))?;
+
+        // Fill the current chunk, and seal it once full: the last chunk is always the short one
+        let len = buf.len().min(CHUNK_LEN - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() == CHUNK_LEN {
+            encryptor
+                .encrypt_next(&mut self.buffer)
+                .ok_or_else(|| io::Error::other("encryption failed"))?;
+            self.inner.write_all(&self.buffer)?;
+            self.buffer.clear();
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decrypts a stream written by EncryptWriter. Each chunk is authenticated before any of it is
+/// read out; a stream that was tampered with, cut short, or decrypted with another key fails with
+/// an `InvalidData` error.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    decryptor: Option<Box<dyn StreamDecryptor>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(mut inner: R, key: &RepoKey) -> io::Result<Self> {
+        match &key.sealing {
+            Sealing::Data(key) => Self::with_data_key(inner, key),
+            Sealing::Recipients {
+                cipher,
+                subkeys,
+                identity: Some(identity),
+                ..
+            } => {
+                let key = open_from_recipients(&mut inner, *cipher, *subkeys, identity)?;
+                Self::with_data_key(inner, &key)
+            }
+            Sealing::Recipients { identity: None, .. } => Err(io::Error::other(
+                "objects sealed to recipients can only be opened with a private key",
+            )),
+        }
+    }
+
+    fn with_data_key(mut inner: R, key: &DataKey) -> io::Result<Self> {
+        let stream_key = match key.subkeys {
+            true => {
+                let mut salt = [0u8; SUBKEY_SALT_LEN];
+                inner.read_exact(&mut salt)?;
+                key.subkey(&salt).map_err(io::Error::other)?
+            }
+            false => key.key.clone(),
+        };
+
+        let mut prefix = vec![0u8; key.cipher.nonce_len() - COUNTER_LEN];
+        inner.read_exact(&mut prefix)?;
+
+        let cipher = key.cipher.with_key(&stream_key).map_err(io::Error::other)?;
+        Ok(Self {
+            inner,
+            decryptor: Some(cipher.decryptor(&prefix)),
+            chunk: Vec::with_capacity(CHUNK_LEN + TAG_LEN),
+            pos: 0,
+        })
+    }
+
+    // Read and open the next chunk
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, STREAM_ERROR);
+
+        self.chunk.resize(CHUNK_LEN + TAG_LEN, 0);
+        let len = read_full(&mut self.inner, &mut self.chunk)?;
+        self.chunk.truncate(len);
+        self.pos = 0;
+
+        // Only the last chunk is short; a stream cut right after a full chunk ends up here with
+        // nothing to open, and fails
+        match (len == CHUNK_LEN + TAG_LEN, self.decryptor.as_mut()) {
+            (true, Some(decryptor)) => decryptor.decrypt_next(&mut self.chunk).ok_or_else(invalid),
+            _ => {
+                let decryptor = self.decryptor.take().ok_or_else(invalid)?;
+                decryptor
+                    .decrypt_last(&mut self.chunk)
+                    .ok_or_else(invalid)?;
+
+                // Nothing may follow the last chunk
+                match self.inner.read(&mut [0u8; 1])? {
+                    0 => Ok(()),
+                    _ => Err(invalid()),
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            // The last chunk was read out
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+// Read until the buffer is full, or the end of the input
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(len)
+}
+
+// Whether an encrypted object was written under this key; its first chunk is enough to tell
+fn encrypted_with(object: impl Read, key: &DataKey) -> bool {
+    DecryptReader::with_data_key(object, key)
+        .and_then(|mut reader| reader.read(&mut [0u8; 1]))
+        .is_ok()
+}
+
+/// A key rotation under way, for objects that aren't files of their own
+pub struct Rotation {
+    old_key: DataKey,
+    new_key: DataKey,
+}
+
+impl Rotation {
+    /// Re-encrypt the object `name` under the new key. Returns None if it isn't encrypted, or is
+    /// under the new key already.
+    pub fn reencrypt(&self, name: &str, object: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !name.ends_with(".enc") || encrypted_with(object, &self.new_key) {
+            return Ok(None);
+        }
+
+        let corrupted = || OpenBrsError::Corrupted(PathBuf::from(name));
+        let mut reader =
+            DecryptReader::with_data_key(object, &self.old_key).map_err(|_| corrupted())?;
+        let mut writer = EncryptWriter::with_data_key(Vec::new(), &self.new_key).at(name)?;
+        io::copy(&mut reader, &mut writer).map_err(|_| corrupted())?;
+
+        writer.finish().at(name).map(Some)
+    }
+}
+
+// Decrypt an object, and encrypt it again under another key, in its place
+fn reencrypt(path: &Path, old_key: &DataKey, new_key: &DataKey) -> Result<()> {
+    let mut tmp = path.as_os_str().to_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut reader = DecryptReader::with_data_key(File::open(path).at(path)?, old_key)
+        .map_err(|_| OpenBrsError::Corrupted(path.to_path_buf()))?;
+    let mut writer =
+        EncryptWriter::with_data_key(File::create(&tmp).at(&tmp)?, new_key).at(&tmp)?;
+
+    io::copy(&mut reader, &mut writer).map_err(|e| match e.kind() {
+        io::ErrorKind::InvalidData => OpenBrsError::Corrupted(path.to_path_buf()),
+        _ => OpenBrsError::Io {
+            path: path.to_path_buf(),
+            source: e,
+        },
+    })?;
+
+    let file = writer.finish().at(&tmp)?;
+    file.sync_all().at(&tmp)?;
+    fs::rename(&tmp, path).at(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CIPHERS: [Cipher; 4] = [
+        Cipher::Aes128Gcm,
+        Cipher::Aes256Gcm,
+        Cipher::Aes256GcmSiv,
+        Cipher::XChaCha20Poly1305,
+    ];
+
+    fn data_key(cipher: Cipher) -> RepoKey {
+        RepoKey {
+            sealing: Sealing::Data(DataKey::generate(cipher).unwrap()),
+            id_key: None,
+        }
+    }
+
+    fn encrypt(content: &[u8], key: &RepoKey) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), key).unwrap();
+        writer.write_all(content).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(stream: &[u8], key: &RepoKey) -> io::Result<Vec<u8>> {
+        let mut content = Vec::new();
+        DecryptReader::new(stream, key)?.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    // The salt and nonce prefix the stream starts with
+    fn prefix_len(cipher: Cipher) -> usize {
+        SUBKEY_SALT_LEN + cipher.nonce_len() - COUNTER_LEN
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn assert_invalid(result: io::Result<Vec<u8>>) {
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn round_trip() {
+        for cipher in CIPHERS {
+            let key = data_key(cipher);
+            for len in [0, 1, CHUNK_LEN - 1, CHUNK_LEN + 1, 3 * CHUNK_LEN / 2] {
+                let content = content(len);
+                assert_eq!(decrypt(&encrypt(&content, &key), &key).unwrap(), content);
+            }
+        }
+    }
+
+    #[test]
+    fn exact_multiple_of_chunk_len() {
+        for cipher in CIPHERS {
+            let key = data_key(cipher);
+            let content = content(2 * CHUNK_LEN);
+            let stream = encrypt(&content, &key);
+
+            // Two full chunks, then an empty last one
+            let full = CHUNK_LEN + TAG_LEN;
+            assert_eq!(stream.len(), prefix_len(cipher) + 2 * full + TAG_LEN);
+            assert_eq!(decrypt(&stream, &key).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn cut_after_a_full_chunk() {
+        for cipher in CIPHERS {
+            let key = data_key(cipher);
+            let full = CHUNK_LEN + TAG_LEN;
+
+            // With the empty last chunk of an exact multiple dropped
+            let stream = encrypt(&content(2 * CHUNK_LEN), &key);
+            assert_invalid(decrypt(&stream[..stream.len() - TAG_LEN], &key));
+
+            // With a short last chunk dropped
+            let stream = encrypt(&content(2 * CHUNK_LEN + 10), &key);
+            assert_invalid(decrypt(&stream[..prefix_len(cipher) + 2 * full], &key));
+            assert_invalid(decrypt(&stream[..prefix_len(cipher) + full], &key));
+        }
+    }
+
+    #[test]
+    fn swapped_chunks() {
+        for cipher in CIPHERS {
+            let key = data_key(cipher);
+            let full = CHUNK_LEN + TAG_LEN;
+            let mut stream = encrypt(&content(2 * CHUNK_LEN + 10), &key);
+
+            let first = prefix_len(cipher);
+            let (head, tail) = stream[first..].split_at_mut(full);
+            head.swap_with_slice(&mut tail[..full]);
+            assert_invalid(decrypt(&stream, &key));
+        }
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        for cipher in CIPHERS {
+            let key = data_key(cipher);
+            for len in [10, CHUNK_LEN] {
+                let mut stream = encrypt(&content(len), &key);
+                stream.push(0);
+                assert_invalid(decrypt(&stream, &key));
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_key() {
+        for cipher in CIPHERS {
+            let stream = encrypt(&content(CHUNK_LEN + 10), &data_key(cipher));
+            assert_invalid(decrypt(&stream, &data_key(cipher)));
+        }
+    }
+
+    #[test]
+    fn streams_have_keys_of_their_own() {
+        for cipher in CIPHERS {
+            let key = DataKey::generate(cipher).unwrap();
+            let content = content(CHUNK_LEN + 10);
+
+            // Under the data key itself, as older repositories encrypt
+            let legacy = RepoKey {
+                sealing: Sealing::Data(DataKey {
+                    cipher,
+                    key: key.key.clone(),
+                    subkeys: false,
+                }),
+                id_key: None,
+            };
+            let stream = encrypt(&content, &legacy);
+            assert_eq!(decrypt(&stream, &legacy).unwrap(), content);
+
+            // A stream can't be read as the other kind
+            let key = RepoKey {
+                sealing: Sealing::Data(key),
+                id_key: None,
+            };
+            assert!(decrypt(&stream, &key).is_err());
+            assert!(decrypt(&encrypt(&content, &key), &legacy).is_err());
+        }
+    }
 }
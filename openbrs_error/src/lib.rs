@@ -0,0 +1,147 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Everything that can go wrong in OpenBRS
+#[derive(Debug, Error)]
+pub enum OpenBrsError {
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+
+    #[error("{}: invalid JSON: {source}", path.display())]
+    Json {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error("{}: invalid TOML: {message}", path.display())]
+    Toml { path: PathBuf, message: String },
+
+    #[error("{}: {reason}", path.display())]
+    InvalidPath { path: PathBuf, reason: &'static str },
+
+    #[error("{} has no repository; run `openbrs init {}` first", .0.display(), .0.display())]
+    NotARepository(PathBuf),
+
+    #[error("{} records no target; give the target along with --repo", .0.display())]
+    NoRecordedTarget(PathBuf),
+
+    #[error("{} backs up {}, not {}", repo.display(), recorded.display(), target.display())]
+    OtherTarget {
+        repo: PathBuf,
+        recorded: PathBuf,
+        target: PathBuf,
+    },
+
+    #[error("{} already exists", .0.display())]
+    AlreadyExists(PathBuf),
+
+    #[error("{} is in use by another openbrs process", .0.display())]
+    Locked(PathBuf),
+
+    #[error("{kind} {id} is missing from the repository")]
+    MissingObject { kind: &'static str, id: String },
+
+    #[error("{kind} {id} doesn't match its id, it was altered")]
+    AlteredObject { kind: &'static str, id: String },
+
+    #[error("{0} does not name exactly one commit")]
+    UnknownCommit(String),
+
+    #[error("there are no backups yet")]
+    NoBackups,
+
+    #[error(
+        "{count} entries could not be restored; the target was left untouched, and the partial restore is in {}",
+        staging.display()
+    )]
+    IncompleteRestore { count: usize, staging: PathBuf },
+
+    #[error("tree {tree} holds an unsafe name: {name}")]
+    UnsafeName { tree: String, name: String },
+
+    #[error("invalid pattern {pattern}: {message}")]
+    InvalidPattern { pattern: String, message: String },
+
+    #[error("nothing matches {0}")]
+    NoMatch(String),
+
+    #[error("{}", .0)]
+    WouldOverwrite(&'static str),
+
+    #[error("wrong password")]
+    WrongPassword,
+
+    #[error("there is no key slot {0}")]
+    UnknownKeySlot(usize),
+
+    #[error("the last key slot can't be removed, nothing could unlock the repository anymore")]
+    LastKeySlot,
+
+    #[error("the repository is sealed to recipients, it has no password or keyfile")]
+    SealedToRecipients,
+
+    #[error("a key rotation was interrupted; run `openbrs rotate-key` to finish it")]
+    RotationInProgress,
+
+    #[error("{} is corrupted, or was not encrypted with this repository's key", .0.display())]
+    Corrupted(PathBuf),
+
+    #[error("{0}")]
+    Crypto(&'static str),
+
+    #[error("{level} is not a {codec} compression level, {levels}")]
+    CompressionLevel {
+        codec: String,
+        level: i32,
+        levels: String,
+    },
+
+    #[error("commit {id} {reason}")]
+    UntrustedCommit { id: String, reason: &'static str },
+}
+
+pub type Result<T> = std::result::Result<T, OpenBrsError>;
+
+/// Attach the path being worked on to I/O and parsing errors, e.g. `fs::read(&path).at(&path)?`
+pub trait WithPath<T> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> WithPath<T> for std::result::Result<T, io::Error> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| OpenBrsError::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl<T> WithPath<T> for std::result::Result<T, serde_json::Error> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| OpenBrsError::Json {
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl<T> WithPath<T> for std::result::Result<T, toml::de::Error> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|e| OpenBrsError::Toml {
+            path: path.as_ref().to_path_buf(),
+            message: e.message().to_string(),
+        })
+    }
+}
+
+impl<T> WithPath<T> for std::result::Result<T, toml::ser::Error> {
+    fn at(self, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|e| OpenBrsError::Toml {
+            path: path.as_ref().to_path_buf(),
+            message: e.to_string(),
+        })
+    }
+}
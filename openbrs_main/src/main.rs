@@ -1,34 +1,1243 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use openbrs_archv_cmprss::{Codec, Compression};
 use openbrs_backup::{backup_diff, backup_full};
-use openbrs_main_structs::FilePath;
-use std::{fs, path::Path};
-fn main() {
-    // Get path
-    let target_path = Path::new("test");
+use openbrs_compare::compare_trees;
+use openbrs_crypto::{
+    Cipher, CommitSigner, KdfParams, KdfPreset, RepoKey, SlotInfo, SlotKind, add_recipient,
+    add_slot, change_password, check_verifying_key, generate_identity, generate_signing_key,
+    has_recipients, init_encryption, init_recipients, label_recipient, label_slot, list_recipients,
+    list_slots, recipients_key, remove_recipient, remove_slot, rotate_key, seals_objects, unlock,
+    unlock_identity,
+};
+use openbrs_error::{OpenBrsError, Result, WithPath};
+use openbrs_main_structs::{ChangeType, Commit, FilePath, RepoConfig, Tree, TrustedKey};
+use openbrs_restore::Pattern;
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{self, Component, Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
 
-    // Ensure that the path is not absolute.
-    if target_path.is_absolute() {
-        panic!("The path is absolute; it must not be absolute, it must be relative")
+// Print a line through the locked standard output, returning the error instead of panicking
+// when the reader went away, as with `openbrs log | head`
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        writeln!(io::stdout().lock(), $($arg)*).at("the standard output")?
+    };
+}
+
+/// OpenBRS, an open backup and restore system
+#[derive(Parser)]
+#[command(
+    name = "openbrs",
+    version,
+    about,
+    after_help = "Exit status: 0 on success, 1 on error, 2 on usage error, 3 when some files were skipped\n\n\
+An encrypted repository is unlocked with the keyfile OPENBRS_KEYFILE names if it is set, otherwise with the \
+password in OPENBRS_PASSWORD, which is asked for when unset; a new password is read from OPENBRS_NEW_PASSWORD. \
+Rotating the key also takes the secret of every other key slot N, from the keyfile OPENBRS_SLOT_N_KEYFILE names \
+or the password in OPENBRS_SLOT_N_PASSWORD, which is asked for when unset. \
+A repository sealed to recipients is backed up without any secret, but with the id key file init wrote, which \
+OPENBRS_ID_KEY names; it is restored with the identity file OPENBRS_IDENTITY names. Both are asked for when unset. \
+Once a repository lists signing keys, backups sign their commits with the key file OPENBRS_SIGNING_KEY names, \
+which is asked for when unset."
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create the .openbrs repository of a target
+    Init(InitArgs),
+
+    /// Generate an identity for repositories sealed to recipients, and print its public key
+    Keygen {
+        /// Where to write the identity, which must not exist; keep it away from the backup hosts
+        output: PathBuf,
+
+        /// Generate a key to sign commits with instead, to keep on the backup host
+        #[arg(long)]
+        signing: bool,
+    },
+
+    /// Back up a target; the first backup of a repository is always a full one
+    Backup {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// Store every file of the target that isn't stored yet, regardless of what has changed
+        #[arg(long, conflicts_with = "diff")]
+        full: bool,
+
+        /// Store only what has changed since HEAD (the default)
+        #[arg(long)]
+        diff: bool,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+    },
+
+    /// Restore a target, or some of its files, from one of its commits
+    Restore(RestoreArgs),
+
+    /// List the commits, from HEAD down to the first backup
+    Log(TargetArg),
+
+    /// Show what has changed in the target since HEAD
+    Status(TargetArg),
+
+    /// Change the password of an encrypted repository; the objects are left as they are
+    ChangePassword(TargetArg),
+
+    /// Re-encrypt every object of an encrypted repository under a new key; resumes if interrupted
+    RotateKey(TargetArg),
+
+    /// Pack any loose objects, and merge the packs that aren't full into as few ones as they fit in
+    Repack {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// Fill packs up to this many MiB from now on, instead of the size the repository records
+        #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(1..))]
+        pack_size: Option<u64>,
+    },
+
+    /// Manage the key slots of an encrypted repository, any of which unlocks it
+    #[command(subcommand)]
+    Key(KeyCommand),
+
+    /// Manage the keys commits must be signed with; with none, commits aren't signed
+    #[command(subcommand)]
+    Signer(SignerCommand),
+
+    /// Check that every commit from HEAD down is intact and signed by a trusted key
+    VerifyHistory {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// Trust this public key rather than those the repository lists, which whoever can write to
+        /// it could replace; may be repeated
+        #[arg(long, value_name = "PUBLIC_KEY")]
+        public_key: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SignerCommand {
+    /// Require commits to be signed, by this key or any other one listed
+    Add {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// The public key `keygen --signing` printed
+        #[arg(long)]
+        public_key: String,
+
+        /// Name the key
+        #[arg(long, default_value = "default")]
+        label: String,
+    },
+
+    /// List the keys commits may be signed with
+    List(TargetArg),
+
+    /// Stop trusting a key; commits it signed won't verify anymore
+    Remove {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// The key, as numbered by `signer list`
+        #[arg(long)]
+        index: usize,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CipherArg {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+
+    /// Resists nonce reuse
+    #[value(name = "aes-256-gcm-siv")]
+    Aes256GcmSiv,
+
+    /// Random nonces long enough never to repeat; fast on machines without AES instructions
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum CodecArg {
+    /// Levels 1 to 22, 3 by default
+    Zstd,
+
+    /// Levels 0 to 9, 6 by default; the smallest, and the slowest
+    Xz,
+
+    /// Levels 0 to 9, 6 by default
+    Gzip,
+
+    /// The fastest; takes no level
+    Lz4,
+
+    /// Store chunks as they are
+    None,
+}
+
+impl From<CodecArg> for Codec {
+    fn from(codec: CodecArg) -> Self {
+        match codec {
+            CodecArg::Zstd => Codec::Zstd,
+            CodecArg::Xz => Codec::Xz,
+            CodecArg::Gzip => Codec::Gzip,
+            CodecArg::Lz4 => Codec::Lz4,
+            CodecArg::None => Codec::None,
+        }
+    }
+}
+
+impl From<CipherArg> for Cipher {
+    fn from(cipher: CipherArg) -> Self {
+        match cipher {
+            CipherArg::Aes256Gcm => Cipher::Aes256Gcm,
+            CipherArg::Aes256GcmSiv => Cipher::Aes256GcmSiv,
+            CipherArg::XChaCha20Poly1305 => Cipher::XChaCha20Poly1305,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum KdfArg {
+    /// 32 MiB of memory, for slow machines
+    Interactive,
+
+    /// 128 MiB of memory
+    Moderate,
+
+    /// 1 GiB of memory, for secrets worth the wait
+    Paranoid,
+}
+
+impl From<KdfArg> for KdfPreset {
+    fn from(kdf: KdfArg) -> Self {
+        match kdf {
+            KdfArg::Interactive => KdfPreset::Interactive,
+            KdfArg::Moderate => KdfPreset::Moderate,
+            KdfArg::Paranoid => KdfPreset::Paranoid,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// Add a key slot, unlocked by a new password or a keyfile
+    Add {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// Unlock the new slot with the content of this file instead of a password
+        #[arg(long, value_name = "PATH")]
+        keyfile: Option<PathBuf>,
+
+        /// Name the new slot
+        #[arg(long)]
+        label: String,
+
+        /// How costly deriving the key from the new secret is
+        #[arg(long, value_enum, default_value_t = KdfArg::Moderate)]
+        kdf: KdfArg,
+
+        /// Tune the key derivation to take about this many milliseconds on this machine instead
+        #[arg(long, value_name = "MS", conflicts_with = "kdf")]
+        kdf_time: Option<u64>,
+
+        /// Seal new objects to this public key too, in a repository sealed to recipients
+        #[arg(long, value_name = "PUBLIC_KEY", conflicts_with_all = ["keyfile", "kdf", "kdf_time"])]
+        recipient: Option<String>,
+    },
+
+    /// List the key slots
+    List(TargetArg),
+
+    /// Rename a key slot
+    Label {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// The slot, as numbered by `key list`
+        #[arg(long)]
+        slot: usize,
+
+        /// The slot's new name
+        #[arg(long)]
+        label: String,
+    },
+
+    /// Remove a key slot; the last one is never removed
+    Remove {
+        #[command(flatten)]
+        target: TargetArg,
+
+        /// The slot, as numbered by `key list`
+        #[arg(long)]
+        slot: usize,
+    },
+}
+
+#[derive(Args)]
+struct InitArgs {
+    #[command(flatten)]
+    target: TargetArg,
+
+    /// Encrypt every object with a key derived from a password
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Unlock the encrypted repository with the content of this file instead of a password
+    #[arg(long, value_name = "PATH", requires = "encrypt")]
+    keyfile: Option<PathBuf>,
+
+    /// Name the first key slot
+    #[arg(long, requires = "encrypt", default_value = "default")]
+    label: String,
+
+    /// The cipher suite objects are encrypted with
+    #[arg(long, requires = "encrypt", value_enum, default_value_t = CipherArg::XChaCha20Poly1305)]
+    cipher: CipherArg,
+
+    /// How costly deriving the key from the secret is
+    #[arg(long, requires = "encrypt", value_enum, default_value_t = KdfArg::Moderate)]
+    kdf: KdfArg,
+
+    /// Tune the key derivation to take about this many milliseconds on this machine instead
+    #[arg(long, value_name = "MS", requires = "encrypt", conflicts_with = "kdf")]
+    kdf_time: Option<u64>,
+
+    /// Seal each object to this public key instead of using a password, so that backing up
+    /// takes no secret and only the matching identity restores; may be repeated
+    #[arg(
+        long,
+        value_name = "PUBLIC_KEY",
+        requires_all = ["encrypt", "id_key"],
+        conflicts_with_all = ["keyfile", "kdf", "kdf_time"]
+    )]
+    recipient: Vec<String>,
+
+    /// Where to write the key object ids are hashed with, which the repository only holds sealed to
+    /// the recipients; backing up reads it from the file OPENBRS_ID_KEY names, so keep it on the
+    /// backup hosts
+    #[arg(long, value_name = "PATH", requires = "recipient")]
+    id_key: Option<PathBuf>,
+
+    /// Fill packs up to this many MiB; packs are what objects are stored in [default: 64]
+    #[arg(long, value_name = "MIB", value_parser = clap::value_parser!(u64).range(1..))]
+    pack_size: Option<u64>,
+
+    #[command(flatten)]
+    compression: CompressionArgs,
+}
+
+#[derive(Args)]
+struct CompressionArgs {
+    /// The codec new chunks are compressed with [default: zstd]
+    #[arg(long, value_name = "CODEC", value_enum)]
+    compression: Option<CodecArg>,
+
+    /// The level of the codec, higher compresses more but slower
+    #[arg(long, value_name = "LEVEL", allow_negative_numbers = true)]
+    compression_level: Option<i32>,
+}
+
+impl CompressionArgs {
+    // The compression asked for, over `base`; a level alone keeps the codec of `base`
+    fn over(&self, base: Option<Compression>) -> Option<Compression> {
+        if self.compression.is_none() && self.compression_level.is_none() {
+            return base;
+        }
+
+        let base = base.unwrap_or_default();
+        Some(Compression {
+            codec: self.compression.map_or(base.codec, Codec::from),
+            level: self.compression_level,
+            ..base
+        })
+    }
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    #[command(flatten)]
+    target: TargetArg,
+
+    /// The commit to restore, or a unique prefix of it; defaults to HEAD
+    #[arg(long)]
+    commit: Option<String>,
+
+    /// Only restore what matches this path or glob, relative to the target (e.g. 'config/*.toml')
+    #[arg(long, value_name = "PATTERN")]
+    path: Option<String>,
+
+    /// Restore into this directory instead of the target; without --path, it must be empty or not exist
+    #[arg(long, value_name = "DIR")]
+    to: Option<PathBuf>,
+
+    /// Write the matching files to the standard output
+    #[arg(long, requires = "path", conflicts_with = "to")]
+    stdout: bool,
+
+    /// Overwrite the live target, when no --to is given
+    #[arg(long, conflicts_with_all = ["to", "stdout"])]
+    force: bool,
+}
+
+#[derive(Args)]
+struct TargetArg {
+    /// The directory or file to back up; may be left out with --repo, which records it
+    #[arg(required_unless_present = "repo")]
+    target: Option<PathBuf>,
+
+    /// The repository, kept in this directory instead of in a .openbrs next to the target
+    #[arg(long, value_name = "DIR")]
+    repo: Option<PathBuf>,
+}
+
+// Each command returns what it had to skip, which doesn't stop it
+type Skipped = Vec<OpenBrsError>;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Init(args) => init(args),
+        Command::Keygen { output, signing } => keygen(&output, signing),
+        Command::Backup {
+            target,
+            full,
+            compression,
+            ..
+        } => backup(&target, full, &compression),
+        Command::Restore(args) => restore(args),
+        Command::Log(arg) => log(&arg),
+        Command::Status(arg) => status(&arg),
+        Command::ChangePassword(arg) => password(&arg),
+        Command::RotateKey(arg) => rotate(&arg),
+        Command::Repack { target, pack_size } => repack(&target, pack_size),
+        Command::Key(command) => key(command),
+        Command::Signer(command) => signer(command),
+        Command::VerifyHistory { target, public_key } => verify_history(&target, public_key),
+    };
+
+    match result {
+        Ok(skipped) if skipped.is_empty() => ExitCode::SUCCESS,
+        Ok(skipped) => {
+            for e in &skipped {
+                eprintln!("openbrs: skipped {e}");
+            }
+            eprintln!("openbrs: {} entries were skipped", skipped.len());
+            ExitCode::from(3)
+        }
+        // A closed pipe only means the reader has seen enough
+        Err(OpenBrsError::Io { source, .. }) if source.kind() == io::ErrorKind::BrokenPipe => {
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("openbrs: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Check the target, then make an instance of its paths
+// A repository given with --repo records its target as an absolute path, so that it can be used
+// from any directory, with or without naming the target again
+fn target_paths(arg: &TargetArg) -> Result<FilePath> {
+    let Some(repo) = &arg.repo else {
+        let target = arg.target.as_deref().unwrap_or(Path::new("."));
+        return FilePath::new(target);
+    };
+    let repo = absolute(repo)?;
+
+    let Some(target) = &arg.target else {
+        return FilePath::from_repo(&repo);
+    };
+    let target = absolute(target)?;
+
+    let paths = FilePath::with_repo(&target, &repo)?;
+    match RepoConfig::read(&paths)?.target {
+        Some(recorded) if absolute(&recorded)? != target => Err(OpenBrsError::OtherTarget {
+            repo,
+            recorded,
+            target,
+        }),
+        _ => Ok(paths),
+    }
+}
+
+// A path made absolute, with each `..` taking away the component before it, so that one directory
+// has one path however it's named
+fn absolute(path: &Path) -> Result<PathBuf> {
+    let mut absolute = PathBuf::new();
+    for component in path::absolute(path).at(path)?.components() {
+        match component {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            Component::CurDir => {}
+            component => absolute.push(component),
+        }
+    }
+    Ok(absolute)
+}
+
+// Same as target_paths, but the repository must already exist
+fn open_repo(arg: &TargetArg) -> Result<FilePath> {
+    let paths = target_paths(arg)?;
+
+    if !paths.main.is_dir() {
+        return Err(OpenBrsError::NotARepository(paths.target));
+    }
+
+    Ok(paths)
+}
+
+// The password of an encrypted repository; the environment variable lets backups run unattended
+fn read_password(prompt: &str, var: &str) -> Result<String> {
+    let password = match env::var(var) {
+        Ok(password) => password,
+        Err(_) => rpassword::prompt_password(prompt).at("the terminal")?,
+    };
+
+    if password.is_empty() {
+        return Err(OpenBrsError::Crypto("the password is empty"));
+    }
+
+    Ok(password)
+}
+
+// A password being set, which is asked for twice when typed in
+fn new_password(var: &str) -> Result<String> {
+    let password = read_password("New password: ", var)?;
+    if env::var_os(var).is_none() && read_password("Repeat the password: ", var)? != password {
+        return Err(OpenBrsError::Crypto("the passwords do not match"));
+    }
+
+    Ok(password)
+}
+
+fn read_keyfile(path: &Path) -> Result<Vec<u8>> {
+    let secret = fs::read(path).at(path)?;
+
+    if secret.is_empty() {
+        return Err(OpenBrsError::Crypto("the keyfile is empty"));
+    }
+
+    Ok(secret)
+}
+
+// The secret unlocking an encrypted repository: a keyfile, or a password
+fn read_secret(prompt: &str) -> Result<Vec<u8>> {
+    match env::var_os("OPENBRS_KEYFILE") {
+        Some(path) => read_keyfile(Path::new(&path)),
+        None => Ok(read_password(prompt, "OPENBRS_PASSWORD")?.into_bytes()),
+    }
+}
+
+// The secret of a new key slot: the keyfile if one is given, a new password otherwise
+fn new_secret(keyfile: Option<&Path>, var: &str) -> Result<(Vec<u8>, SlotKind)> {
+    match keyfile {
+        Some(path) => Ok((read_keyfile(path)?, SlotKind::Keyfile)),
+        None => Ok((new_password(var)?.into_bytes(), SlotKind::Password)),
+    }
+}
+
+// Same as open_repo, but the repository must be encrypted
+fn open_encrypted_repo(target: &TargetArg) -> Result<FilePath> {
+    let paths = open_repo(target)?;
+
+    if !paths.crypto.exists() {
+        return Err(OpenBrsError::Crypto("the repository is not encrypted"));
+    }
+
+    Ok(paths)
+}
+
+// Same as open_encrypted_repo, but the repository must be unlocked by key slots, so that no secret
+// is asked for in vain
+fn open_slotted_repo(target: &TargetArg) -> Result<FilePath> {
+    let paths = open_encrypted_repo(target)?;
+
+    if has_recipients(&paths.crypto)? {
+        return Err(OpenBrsError::SealedToRecipients);
+    }
+
+    Ok(paths)
+}
+
+// The content of a key file, named by an environment variable, or asked for
+fn read_key_file(prompt: &str, var: &str) -> Result<String> {
+    let path = match env::var_os(var) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprint!("{prompt}");
+            let mut path = String::new();
+            io::stdin().read_line(&mut path).at("the terminal")?;
+            PathBuf::from(path.trim_end())
+        }
+    };
+
+    fs::read_to_string(&path).at(&path)
+}
+
+// The id key of a repository sealed to recipients, which its backup hosts keep
+fn read_id_key() -> Result<String> {
+    read_key_file("Id key file: ", "OPENBRS_ID_KEY")
+}
+
+// The key commits are signed with, if the repository lists any; it must be one of them
+fn commit_signer(paths: &FilePath) -> Result<Option<CommitSigner>> {
+    let config = RepoConfig::read(paths)?;
+    if config.signing_keys.is_empty() {
+        return Ok(None);
+    }
+
+    let signer = CommitSigner::new(&read_key_file("Signing key file: ", "OPENBRS_SIGNING_KEY")?)?;
+    let public_key = signer.public_key();
+    if !config
+        .signing_keys
+        .iter()
+        .any(|trusted| trusted.public_key == public_key)
+    {
+        return Err(OpenBrsError::Crypto(
+            "the signing key isn't one the repository lists",
+        ));
+    }
+
+    Ok(Some(signer))
+}
+
+// The key objects are decrypted with, if the repository is encrypted
+fn repo_key(paths: &FilePath) -> Result<Option<RepoKey>> {
+    if !paths.crypto.exists() {
+        return Ok(None);
+    }
+
+    // Only an identity opens objects sealed to recipients
+    if has_recipients(&paths.crypto)? {
+        let identity = read_key_file("Identity file: ", "OPENBRS_IDENTITY")?;
+        return unlock_identity(&identity, &paths.crypto).map(Some);
+    }
+
+    let secret = read_secret("Password: ")?;
+    unlock(&secret, &paths.crypto).map(Some)
+}
+
+// The key new objects are encrypted with: same as repo_key, but the recipients' public keys are
+// enough to seal to them
+fn sealing_key(paths: &FilePath) -> Result<Option<RepoKey>> {
+    if paths.crypto.exists() && has_recipients(&paths.crypto)? {
+        return recipients_key(&paths.crypto, read_id_key).map(Some);
+    }
+
+    repo_key(paths)
+}
+
+// The key trees and commits are read with, only needed if the repository seals them
+fn objects_key(paths: &FilePath) -> Result<Option<RepoKey>> {
+    if paths.crypto.exists() && seals_objects(&paths.crypto)? {
+        return repo_key(paths);
+    }
+
+    Ok(None)
+}
+
+// The KDF parameters of a new key slot: a preset, or calibrated to a time on this machine
+fn kdf_params(kdf: KdfArg, kdf_time: Option<u64>) -> KdfParams {
+    match kdf_time {
+        Some(ms) => {
+            let params = KdfParams::calibrate(Duration::from_millis(ms));
+            eprintln!("Calibrated the key derivation to {params}");
+            params
+        }
+        None => KdfParams::preset(kdf.into()),
+    }
+}
+
+fn init(args: InitArgs) -> Result<Skipped> {
+    let paths = target_paths(&args.target)?;
+    let recipients = &args.recipient;
+
+    // A directory made for the repository beforehand is used as long as it's empty
+    let made = paths.main.exists();
+    if made && fs::read_dir(&paths.main).at(&paths.main)?.next().is_some() {
+        return Err(match paths.main.join("objects").exists() {
+            true => OpenBrsError::AlreadyExists(paths.main),
+            false => OpenBrsError::InvalidPath {
+                path: paths.main,
+                reason: "is not empty",
+            },
+        });
+    }
+
+    let compression = args.compression.over(None);
+    if let Some(compression) = &compression {
+        compression.check()?;
+    }
+
+    // Ask for the secret before creating anything, so that a typo leaves nothing behind
+    let secret = match args.encrypt && recipients.is_empty() {
+        true => Some(new_secret(args.keyfile.as_deref(), "OPENBRS_PASSWORD")?),
+        false => None,
+    };
+
+    paths.create_dirs()?;
+    let cipher = args.cipher.into();
+    let encrypted = match secret {
+        Some((secret, kind)) => {
+            let kdf = kdf_params(args.kdf, args.kdf_time);
+            init_encryption(&secret, kind, &args.label, kdf, cipher, &paths.crypto).map(|_| ())
+        }
+        // --recipient requires --id-key
+        None => match &args.id_key {
+            Some(path) => init_recipients(recipients, &args.label, cipher, &paths.crypto)
+                .and_then(|id_key| write_key_file(path, &id_key)),
+            None => Ok(()),
+        },
+    };
+
+    // A mistyped public key shouldn't leave an unencrypted repository behind, nor anything in the
+    // directory made for it
+    if let Err(e) = encrypted {
+        let _ = match made {
+            true => fs::read_dir(&paths.main).and_then(|entries| {
+                entries
+                    .flatten()
+                    .try_for_each(|entry| match entry.path().is_dir() {
+                        true => fs::remove_dir_all(entry.path()),
+                        false => fs::remove_file(entry.path()),
+                    })
+            }),
+            false => fs::remove_dir_all(&paths.main),
+        };
+        return Err(e);
+    }
+
+    // A repository kept outside of its target records where the target is
+    let target = args.target.repo.as_ref().map(|_| paths.target.clone());
+    if args.pack_size.is_some() || compression.is_some() || target.is_some() {
+        let config = RepoConfig {
+            pack_size: args.pack_size.map(|mib| mib * 1024 * 1024),
+            compression,
+            target,
+            ..RepoConfig::default()
+        };
+        config.write(&paths)?;
     }
+    outln!(
+        "Initialized an empty repository in {}",
+        paths.main.display()
+    );
+
+    Ok(Vec::new())
+}
+
+// Write a new identity or signing key, readable by its owner only, and print its public key
+fn keygen(output: &Path, signing: bool) -> Result<Skipped> {
+    let (identity, public_key) = match signing {
+        true => generate_signing_key()?,
+        false => generate_identity()?,
+    };
+    write_key_file(output, &identity)?;
 
-    // Make an instance of paths
-    let paths = FilePath::new(&target_path.to_path_buf());
+    outln!("{public_key}");
+    Ok(Vec::new())
+}
+
+// Write a key to a new file, readable by its owner only
+fn write_key_file(path: &Path, key: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).at(path)?;
+    writeln!(file, "{key}").at(path)
+}
+
+fn backup(target: &TargetArg, full: bool, compression: &CompressionArgs) -> Result<Skipped> {
+    let paths = open_repo(target)?;
+    let _lock = paths.lock()?;
+
+    // The flags only hold for this backup, the repository's config otherwise
+    let recorded = RepoConfig::read(&paths)?.compression;
+    let compression = compression.over(recorded).unwrap_or_default();
+    compression.check()?;
 
-    // Create paths if they dont exist
-    let _first_backup = match fs::exists(&paths.main) {
-        Ok(response) => {
-            if !response {
-                paths.create_dirs();
+    // Without a HEAD, there is nothing to compare against
+    let first_backup = paths.read_head()?.is_none();
+    let key = sealing_key(&paths)?;
+    let signer = commit_signer(&paths)?;
+
+    // A host sealing to recipients can't read back HEAD's tree to compare against, so each of its
+    // backups is a full one
+    let full = full
+        || key
+            .as_ref()
+            .is_some_and(|key| key.id_key().is_some() && !key.can_decrypt());
+
+    let skipped = if full {
+        backup_full(&paths, &compression, signer.as_ref(), key.as_ref())?
+    } else {
+        backup_diff(
+            &paths,
+            first_backup,
+            &compression,
+            signer.as_ref(),
+            key.as_ref(),
+        )?
+    };
+
+    if let Some(head) = paths.read_head()? {
+        outln!("HEAD is now at {head}");
+    }
+
+    Ok(skipped)
+}
+
+fn restore(args: RestoreArgs) -> Result<Skipped> {
+    let paths = open_repo(&args.target)?;
+    let _lock = paths.lock()?;
+
+    // Find the commit to restore
+    let commit_id = match args.commit {
+        Some(prefix) => {
+            Commit::resolve(&prefix, &paths)?.ok_or(OpenBrsError::UnknownCommit(prefix))?
+        }
+        None => paths.read_head()?.ok_or(OpenBrsError::NoBackups)?,
+    };
+    let key = repo_key(&paths)?;
+
+    if let Some(pattern) = args.path {
+        return restore_matching(
+            &paths,
+            &commit_id,
+            &pattern,
+            args.to,
+            args.stdout,
+            args.force,
+            key.as_ref(),
+        );
+    }
+
+    match args.to {
+        Some(destination) => {
+            // Never merge a restore into existing data
+            let is_empty = match fs::read_dir(&destination) {
+                Ok(mut entries) => entries.next().is_none(),
+                Err(_) => !destination.exists(),
             };
-            !response
+            if !is_empty {
+                return Err(OpenBrsError::WouldOverwrite("the destination is not empty"));
+            }
+
+            let skipped = openbrs_restore::restore(&paths, &commit_id, &destination, key.as_ref())?;
+            outln!("Restored {commit_id} into {}", destination.display());
+            Ok(skipped)
         }
-        Err(_) => {
-            paths.create_dirs();
-            true
+        None if args.force => {
+            openbrs_restore::restore_in_place(&paths, &commit_id, key.as_ref())?;
+            outln!("Restored {commit_id} over {}", paths.target.display());
+            Ok(Vec::new())
+        }
+        None => Err(OpenBrsError::WouldOverwrite(
+            "restoring would overwrite the target; pass --to <DIR>, or --force",
+        )),
+    }
+}
+
+// Restore only what matches a path or a glob
+fn restore_matching(
+    paths: &FilePath,
+    commit_id: &str,
+    pattern: &str,
+    to: Option<PathBuf>,
+    stdout: bool,
+    force: bool,
+    key: Option<&RepoKey>,
+) -> Result<Skipped> {
+    let pattern = pattern.trim_start_matches("./");
+    let pattern = Pattern::new(pattern).map_err(|e| OpenBrsError::InvalidPattern {
+        pattern: pattern.to_string(),
+        message: e.to_string(),
+    })?;
+
+    let matches = openbrs_restore::find_matches(paths, commit_id, &pattern, key)?;
+    if matches.is_empty() {
+        return Err(OpenBrsError::NoMatch(pattern.to_string()));
+    }
+
+    if stdout {
+        // Directories have no content to write out
+        if let Some(dir) = matches.iter().find(|entry| entry.is_dir) {
+            return Err(OpenBrsError::InvalidPath {
+                path: dir.path.clone(),
+                reason: "is a directory",
+            });
+        }
+
+        let mut out = io::stdout().lock();
+        for entry in &matches {
+            openbrs_restore::restore_file_to_writer(paths, commit_id, &entry.path, &mut out, key)?;
+        }
+        return Ok(Vec::new());
+    }
+
+    let destination = match to {
+        Some(destination) => {
+            // Never overwrite existing data, unless restoring over the target was asked for
+            if let Some(entry) = matches
+                .iter()
+                .find(|entry| destination.join(&entry.path).symlink_metadata().is_ok())
+            {
+                return Err(OpenBrsError::AlreadyExists(destination.join(&entry.path)));
+            }
+            destination
+        }
+        None if force => paths.parent.clone(),
+        None => {
+            return Err(OpenBrsError::WouldOverwrite(
+                "restoring would overwrite the target; pass --to <DIR>, --stdout, or --force",
+            ));
         }
     };
 
-    let _passwd = "test_passwd".as_bytes();
+    let skipped = openbrs_restore::restore_matches(paths, commit_id, &matches, &destination, key)?;
+    for entry in &matches {
+        outln!("Restored {}", destination.join(&entry.path).display());
+    }
+
+    Ok(skipped)
+}
+
+fn log(target: &TargetArg) -> Result<Skipped> {
+    let paths = open_repo(target)?;
+    let key = objects_key(&paths)?;
+
+    // Walk from HEAD through the parents
+    let mut next = paths.read_head()?;
+    if next.is_none() {
+        outln!("No backups yet");
+    }
+
+    while let Some(id) = next {
+        let commit = Commit::read(&id, &paths, key.as_ref())?;
+
+        outln!("commit {}", commit.id);
+        outln!("tree   {}", commit.tree_id);
+        if let Some(time) = commit.time {
+            outln!("date   {}", local_time(time));
+        }
+        outln!("\n    {}\n", commit.message);
+
+        next = commit.parent;
+    }
+
+    Ok(Vec::new())
+}
+
+// A time in seconds since the epoch, in the local time zone, e.g. "Tue Oct 13 21:04:05 2026 +0200"
+fn local_time(time: i64) -> String {
+    let seconds = time as libc::time_t;
+    // SAFETY: an all-zero tm is valid, localtime_r only fills it, and strftime writes at most
+    // buf.len() bytes into buf from a NUL-terminated format
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&seconds, &mut tm).is_null() {
+            return time.to_string();
+        }
+        let mut buf = [0u8; 64];
+        let format = c"%a %b %e %H:%M:%S %Y %z";
+        let len = libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm);
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+}
+
+fn verify_history(target: &TargetArg, public_keys: Vec<String>) -> Result<Skipped> {
+    let paths = open_repo(target)?;
+
+    let trusted = match public_keys.is_empty() {
+        true => RepoConfig::read(&paths)?
+            .signing_keys
+            .into_iter()
+            .map(|trusted| trusted.public_key)
+            .collect(),
+        false => public_keys,
+    };
+    if trusted.is_empty() {
+        return Err(OpenBrsError::Crypto(
+            "the repository lists no signing keys; pass --public-key",
+        ));
+    }
+
+    // Walk from HEAD through the parents, stopping at the first commit that doesn't verify. A
+    // signature covers the commit, which names its tree: the trees and blobs under it must match
+    // their ids for the snapshot to be the one signed.
+    let key = objects_key(&paths)?;
+    let mut count = 0;
+    let mut seen = HashSet::new();
+    let mut next = paths.read_head()?;
+    while let Some(id) = next {
+        let commit = Commit::read(&id, &paths, key.as_ref())?;
+        if let Err(reason) = commit.verify(&id, &trusted, key.as_ref()) {
+            return Err(OpenBrsError::UntrustedCommit { id, reason });
+        }
+        Tree::check(&commit.tree_id, &paths, &mut seen, key.as_ref())?;
+
+        count += 1;
+        next = commit.parent;
+    }
+
+    outln!("{count} commits, all signed by a trusted key");
+    Ok(Vec::new())
+}
+
+fn status(target: &TargetArg) -> Result<Skipped> {
+    let paths = open_repo(target)?;
+    let _lock = paths.lock()?;
+
+    let head = match paths.read_head()? {
+        Some(head) => head,
+        None => {
+            outln!("No backups yet; the next backup will be a full one");
+            return Ok(Vec::new());
+        }
+    };
+
+    // Build the current tree, and compare it against HEAD's
+    let key = objects_key(&paths)?;
+    let key = key.as_ref();
+    let mut skipped = Vec::new();
+    let old_tree = Tree::read(&Commit::read(&head, &paths, key)?.tree_id, &paths, key)?;
+    let new_tree = Tree::build(&paths, Some(&old_tree), &mut skipped, key)?;
+    let changes = compare_trees(&old_tree, &new_tree, &paths, key)?;
+
+    if changes.is_empty() {
+        outln!("Nothing has changed since {head}");
+        return Ok(skipped);
+    }
+
+    // Paths are stored relative to the target, show them as they are from the current directory
+    for change in changes {
+        let kind = match change.change_type {
+            ChangeType::Added => "added",
+            ChangeType::Removed => "removed",
+            ChangeType::Modified => "modified",
+        };
+        outln!("{kind:>10}: {}", paths.entry_path(&change.path).display());
+    }
+
+    Ok(skipped)
+}
+
+fn password(target: &TargetArg) -> Result<Skipped> {
+    let paths = open_slotted_repo(target)?;
+    let _lock = paths.lock()?;
+
+    let old_secret = read_secret("Current password: ")?;
+    let new_password = new_password("OPENBRS_NEW_PASSWORD")?;
+    change_password(&old_secret, new_password.as_bytes(), &paths.crypto)?;
+    outln!("Changed the password of {}", paths.main.display());
+
+    Ok(Vec::new())
+}
+
+fn rotate(target: &TargetArg) -> Result<Skipped> {
+    let paths = open_slotted_repo(target)?;
+    let _lock = paths.lock()?;
+
+    let secret = read_secret("Password: ")?;
+
+    // The new key is wrapped in every slot, which takes each slot's own secret
+    let others = |slot: &SlotInfo| match slot.kind {
+        SlotKind::Password => {
+            let prompt = format!("Password of key slot {} ({}): ", slot.index, slot.label);
+            let var = format!("OPENBRS_SLOT_{}_PASSWORD", slot.index);
+            Ok(read_password(&prompt, &var)?.into_bytes())
+        }
+        SlotKind::Keyfile => match env::var_os(format!("OPENBRS_SLOT_{}_KEYFILE", slot.index)) {
+            Some(path) => read_keyfile(Path::new(&path)),
+            None => {
+                eprint!("Keyfile of key slot {} ({}): ", slot.index, slot.label);
+                let mut path = String::new();
+                io::stdin().read_line(&mut path).at("the terminal")?;
+                read_keyfile(Path::new(path.trim_end()))
+            }
+        },
+    };
+    let dirs = [
+        paths.chunks.as_path(),
+        &paths.blobs,
+        &paths.trees,
+        &paths.commits,
+    ];
+    let pack_size = RepoConfig::read(&paths)?.pack_size();
+    let count = rotate_key(&secret, &paths.crypto, &dirs, others, |rotation| {
+        paths
+            .store
+            .rewrite(pack_size, |name, object| rotation.reencrypt(name, object))
+    })?;
+    outln!("Re-encrypted {count} objects under a new key");
+
+    Ok(Vec::new())
+}
+
+fn repack(target: &TargetArg, pack_size: Option<u64>) -> Result<Skipped> {
+    let paths = open_repo(target)?;
+    let _lock = paths.lock()?;
+
+    let mut config = RepoConfig::read(&paths)?;
+    if let Some(mib) = pack_size {
+        config.pack_size = Some(mib * 1024 * 1024);
+        config.write(&paths)?;
+    }
+
+    // What an interrupted backup, or status, left loose first
+    let loose = paths.store.pack_loose(
+        &[&paths.chunks, &paths.blobs, &paths.trees],
+        config.pack_size(),
+    )?;
+    let (merged, packs) = paths.store.repack(config.pack_size())?;
+    outln!("Packed {loose} loose objects, and merged {merged} packs into {packs}");
+
+    Ok(Vec::new())
+}
+
+fn key(command: KeyCommand) -> Result<Skipped> {
+    match command {
+        KeyCommand::Add {
+            target,
+            keyfile,
+            label,
+            kdf,
+            kdf_time,
+            recipient,
+        } => {
+            if let Some(public_key) = recipient {
+                let paths = open_encrypted_repo(&target)?;
+                let _lock = paths.lock()?;
+                let index = add_recipient(&public_key, &label, &paths.crypto, read_id_key)?;
+                outln!("Added recipient {index} ({label})");
+                return Ok(Vec::new());
+            }
+
+            let paths = open_slotted_repo(&target)?;
+            let _lock = paths.lock()?;
+            let secret = read_secret("Password: ")?;
+            let (new_secret, kind) = new_secret(keyfile.as_deref(), "OPENBRS_NEW_PASSWORD")?;
+            let kdf = kdf_params(kdf, kdf_time);
+            let index = add_slot(&secret, &new_secret, kind, &label, kdf, &paths.crypto)?;
+            outln!("Added key slot {index} ({label})");
+        }
+        KeyCommand::List(arg) => {
+            let paths = open_encrypted_repo(&arg)?;
+            for recipient in list_recipients(&paths.crypto)? {
+                outln!(
+                    "{:>3}  recipient  {}  {}",
+                    recipient.index,
+                    recipient.public_key,
+                    recipient.label
+                );
+            }
+            for slot in list_slots(&paths.crypto)? {
+                let kind = match slot.kind {
+                    SlotKind::Password => "password",
+                    SlotKind::Keyfile => "keyfile",
+                };
+                outln!(
+                    "{:>3}  {kind:<8}  {:<22}  {}",
+                    slot.index,
+                    slot.kdf.to_string(),
+                    slot.label
+                );
+            }
+        }
+        KeyCommand::Label {
+            target,
+            slot,
+            label,
+        } => {
+            let paths = open_encrypted_repo(&target)?;
+            let _lock = paths.lock()?;
+            match has_recipients(&paths.crypto)? {
+                true => label_recipient(slot, &label, &paths.crypto)?,
+                false => label_slot(slot, &label, &paths.crypto)?,
+            }
+        }
+        KeyCommand::Remove { target, slot } => {
+            let paths = open_encrypted_repo(&target)?;
+            let _lock = paths.lock()?;
+
+            // Removing a recipient only changes whom new objects are sealed to
+            if has_recipients(&paths.crypto)? {
+                remove_recipient(slot, &paths.crypto)?;
+                outln!("Removed recipient {slot}");
+                return Ok(Vec::new());
+            }
+
+            let secret = read_secret("Password: ")?;
+            remove_slot(&secret, slot, &paths.crypto)?;
+            outln!("Removed key slot {slot}");
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+fn signer(command: SignerCommand) -> Result<Skipped> {
+    match command {
+        SignerCommand::Add {
+            target,
+            public_key,
+            label,
+        } => {
+            let paths = open_repo(&target)?;
+            let _lock = paths.lock()?;
+            check_verifying_key(&public_key)?;
+
+            let mut config = RepoConfig::read(&paths)?;
+            config.signing_keys.push(TrustedKey {
+                label: label.clone(),
+                public_key: public_key.trim().to_string(),
+            });
+            config.write(&paths)?;
+            outln!(
+                "Added signing key {} ({label})",
+                config.signing_keys.len() - 1
+            );
+        }
+        SignerCommand::List(arg) => {
+            let paths = open_repo(&arg)?;
+            for (index, trusted) in RepoConfig::read(&paths)?.signing_keys.iter().enumerate() {
+                outln!("{index:>3}  {}  {}", trusted.public_key, trusted.label);
+            }
+        }
+        SignerCommand::Remove { target, index } => {
+            let paths = open_repo(&target)?;
+            let _lock = paths.lock()?;
+
+            let mut config = RepoConfig::read(&paths)?;
+            if index >= config.signing_keys.len() {
+                return Err(OpenBrsError::UnknownKeySlot(index));
+            }
+            config.signing_keys.remove(index);
+            config.write(&paths)?;
+            outln!("Removed signing key {index}");
+        }
+    }
 
-    //backup_full(&paths);
-    backup_diff(&paths, true);
+    Ok(Vec::new())
 }
@@ -1,56 +1,194 @@
 //use openbrs_archv_cmprss::{archive_compress_dir, archive_compress_file};
+use fastcdc::v2020::StreamCDC;
+use hmac::{Hmac, Mac};
+use openbrs_archv_cmprss::{Compression, read_chunk};
+use openbrs_crypto::{CommitSigner, DecryptReader, EncryptWriter, RepoKey, verify_signature};
+use openbrs_error::{OpenBrsError, Result, WithPath};
+use openbrs_pack::{DEFAULT_PACK_SIZE, ObjectStore};
 use serde::{Deserialize, Serialize};
-use serde_json;
 use sha3::{Digest, Sha3_256};
 use std::fs::metadata;
 use std::{
-    fs::{self, File},
-    io::Read,
-    path::PathBuf,
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, FileType, OpenOptions, TryLockError},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::AsRawFd,
+        unix::fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
 pub struct FilePath {
     pub target: PathBuf,
     pub parent: PathBuf,
     pub main: PathBuf,
     pub blobs: PathBuf,
+    pub chunks: PathBuf,
     pub trees: PathBuf,
     pub commits: PathBuf,
     pub head: PathBuf,
+    pub crypto: PathBuf, // Present only in encrypted repositories
+    pub config: PathBuf,
+    pub store: ObjectStore, // Finds objects, loose or packed
 }
 
 impl FilePath {
-    pub fn new(target_path: &PathBuf) -> Self {
-        let (main, parent) = if metadata(&target_path).unwrap().is_dir() {
-            (
-                target_path.to_path_buf().join(".openbrs"),
-                target_path.clone(),
-            )
-        } else {
-            (
-                target_path.parent().unwrap().to_path_buf().join(".openbrs"),
-                target_path.parent().unwrap().to_path_buf(),
-            )
-        };
+    pub fn new(target_path: &Path) -> Result<Self> {
+        let parent = target_parent(target_path)?;
+        let main = parent.join(".openbrs");
+        Ok(Self::at(target_path, parent, main))
+    }
+
+    /// The paths of a target whose repository is kept in `main`, wherever it is, instead of in a
+    /// .openbrs next to it
+    pub fn with_repo(target_path: &Path, main: &Path) -> Result<Self> {
+        let parent = target_parent(target_path)?;
+        Ok(Self::at(target_path, parent, main.to_path_buf()))
+    }
+
+    /// The paths of the repository kept in `main`, and of the target its config records
+    pub fn from_repo(main: &Path) -> Result<Self> {
+        let config = RepoConfig::read_file(&main.join("config.toml"))?;
+        let target = config
+            .target
+            .ok_or_else(|| OpenBrsError::NoRecordedTarget(main.to_path_buf()))?;
+        Self::with_repo(&target, main)
+    }
 
+    fn at(target_path: &Path, parent: PathBuf, main: PathBuf) -> Self {
         Self {
-            target: target_path.clone(),
-            parent: parent.clone(),
+            target: target_path.to_path_buf(),
+            parent,
             main: main.clone(),
             blobs: main.join("objects/blobs"),
+            chunks: main.join("objects/chunks"),
             trees: main.join("objects/trees"),
             commits: main.join("objects/commits"),
             head: main.join("HEAD"),
+            crypto: main.join("crypto.toml"),
+            config: main.join("config.toml"),
+            store: ObjectStore::new(&main.join("objects")),
+        }
+    }
+
+    /// Create the repository's directories. Its own may have been made for it already, empty.
+    pub fn create_dirs(&self) -> Result<()> {
+        match fs::create_dir(&self.main) {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && is_empty_dir(&self.main) => {}
+            created => created.at(&self.main)?,
+        }
+        fs::create_dir(self.main.join("objects")).at(self.main.join("objects"))?;
+        fs::create_dir(&self.blobs).at(&self.blobs)?;
+        fs::create_dir(&self.chunks).at(&self.chunks)?;
+        fs::create_dir(&self.trees).at(&self.trees)?;
+        fs::create_dir(&self.commits).at(&self.commits)?;
+        Ok(())
+    }
+
+    /// Lock the repository for a command that changes it, so that no other one does at the same
+    /// time. The lock is let go when it's dropped, or when the process ends, however it ends.
+    pub fn lock(&self) -> Result<RepoLock> {
+        let path = self.main.join("lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .at(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(RepoLock { _file: file }),
+            Err(TryLockError::WouldBlock) => Err(OpenBrsError::Locked(self.main.clone())),
+            Err(TryLockError::Error(e)) => Err(e).at(&path),
         }
     }
 
-    pub fn create_dirs(&self) {
-        fs::create_dir(&self.main).unwrap();
-        fs::create_dir(&self.main.join("objects")).unwrap();
-        fs::create_dir(&self.blobs).unwrap();
-        fs::create_dir(&self.trees).unwrap();
-        fs::create_dir(&self.commits).unwrap();
+    /// The ID of the commit HEAD points to, if any backup has been made yet
+    pub fn read_head(&self) -> Result<Option<String>> {
+        match fs::read_to_string(&self.head) {
+            Ok(id) => Ok(Some(id.trim().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).at(&self.head),
+        }
+    }
+
+    /// Point HEAD to a new commit
+    pub fn write_head(&self, commit_id: &str) -> Result<()> {
+        // Write the ID to a temporary file first, then rename it over HEAD: a rename is atomic, so an
+        // interrupted backup can never leave HEAD empty or half-written.
+        let tmp = self.main.join("HEAD.tmp");
+        let file = File::create(&tmp).at(&tmp)?;
+        (&file).write_all(commit_id.as_bytes()).at(&tmp)?;
+        file.sync_all().at(&tmp)?;
+
+        fs::rename(&tmp, &self.head).at(&self.head)
+    }
+
+    /// Where an entry of a tree is on disk, from the path it's stored under: relative to the
+    /// target, or the file's name for a file target
+    pub fn entry_path(&self, path: &Path) -> PathBuf {
+        self.parent.join(path)
+    }
+}
+
+/// The lock of a repository, held until dropped
+pub struct RepoLock {
+    _file: File,
+}
+
+/// The settings of a repository, kept in its config.toml; a missing file means the defaults
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RepoConfig {
+    // Commits must be signed by one of these keys; none means commits aren't signed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signing_keys: Vec<TrustedKey>,
+
+    // The size packs are filled up to, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pack_size: Option<u64>,
+
+    // How new chunks are compressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+
+    // The target, as an absolute path, of a repository kept outside of it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+}
+
+/// An Ed25519 public key, in Base64
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedKey {
+    pub label: String,
+    pub public_key: String,
+}
+
+impl RepoConfig {
+    pub fn read(paths: &FilePath) -> Result<Self> {
+        Self::read_file(&paths.config)
+    }
+
+    fn read_file(config: &Path) -> Result<Self> {
+        match fs::read_to_string(config) {
+            Ok(toml_string) => toml::from_str(&toml_string).at(config),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).at(config),
+        }
+    }
+
+    pub fn pack_size(&self) -> u64 {
+        self.pack_size.unwrap_or(DEFAULT_PACK_SIZE)
+    }
+
+    pub fn write(&self, paths: &FilePath) -> Result<()> {
+        let toml_string = toml::to_string(self).at(&paths.config)?;
+
+        // Same as HEAD, never leave it half written
+        let tmp = paths.main.join("config.toml.tmp");
+        fs::write(&tmp, toml_string).at(&tmp)?;
+        fs::rename(&tmp, &paths.config).at(&paths.config)
     }
 }
 
@@ -61,79 +199,290 @@ pub struct Commit {
     pub tree_id: String,        // Root tree id, which is the hash of its content
     pub parent: Option<String>, // Previous commit (None for the initial backup)
     pub message: String,        // Commit message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<i64>, // When it was made, in seconds since the epoch; None for older commits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>, // Ed25519, over signed_message
 }
 
 impl Commit {
-    pub fn new(tree_id: String, parent: Option<String>, message: String) -> Self {
+    pub fn new(
+        tree_id: String,
+        parent: Option<String>,
+        message: String,
+        key: Option<&RepoKey>,
+    ) -> Self {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|elapsed| i64::try_from(elapsed.as_secs()).ok());
+        let id = Commit::calc_id(&tree_id, parent.as_deref(), &message, time, key);
+
+        // Return the commit
+        Self {
+            id,
+            tree_id,
+            parent,
+            message,
+            time,
+            signature: None,
+        }
+    }
+
+    fn calc_id(
+        tree_id: &str,
+        parent: Option<&str>,
+        message: &str,
+        time: Option<i64>,
+        key: Option<&RepoKey>,
+    ) -> String {
         // Create a hasher to create the ID
-        let mut hasher = Sha3_256::new();
+        let mut hasher = IdHasher::new(key);
 
         // Append the tree_id first
         hasher.update(tree_id.as_bytes());
 
         // Append the parent's id, of any
-        if let Some(ref p) = parent {
+        if let Some(p) = parent {
             hasher.update(p.as_bytes());
         }
 
         // Append the commit's message
         hasher.update(message.as_bytes());
 
-        // Hash the serial, and encode it in Base64
-        let id = hex::encode(hasher.finalize());
+        // And when it was made; commits from before times were recorded have none
+        if let Some(time) = time {
+            hasher.update(format!(":time={time}"));
+        }
 
-        // Return the commit
-        Self {
-            id,
-            tree_id,
-            parent,
-            message,
+        // Hash the serial, and encode it in hex
+        hasher.finalize()
+    }
+
+    // What a signature covers: every field, one per line, the message last as it may span several
+    fn signed_message(&self) -> Vec<u8> {
+        let time = match self.time {
+            Some(time) => format!("time {time}\n"),
+            None => String::new(),
+        };
+        format!(
+            "openbrs commit\nid {}\ntree {}\nparent {}\n{time}{}",
+            self.id,
+            self.tree_id,
+            self.parent.as_deref().unwrap_or(""),
+            self.message
+        )
+        .into_bytes()
+    }
+
+    pub fn sign(&mut self, signer: &CommitSigner) {
+        self.signature = Some(signer.sign(&self.signed_message()));
+    }
+
+    /// Check that the commit read as `id` is really that commit, and that one of `trusted` signed
+    /// it. Returns what's wrong with it, if anything.
+    pub fn verify(
+        &self,
+        id: &str,
+        trusted: &[String],
+        key: Option<&RepoKey>,
+    ) -> std::result::Result<(), &'static str> {
+        let calc_id = Commit::calc_id(
+            &self.tree_id,
+            self.parent.as_deref(),
+            &self.message,
+            self.time,
+            key,
+        );
+        if self.id != id || calc_id != id {
+            return Err("doesn't match its id");
+        }
+
+        let signature = self.signature.as_deref().ok_or("is unsigned")?;
+        let message = self.signed_message();
+        match trusted
+            .iter()
+            .any(|public_key| verify_signature(public_key, &message, signature))
+        {
+            true => Ok(()),
+            false => Err("is badly signed, or not by a trusted key"),
         }
     }
 
-    pub fn write(&self, paths: &FilePath) {
+    pub fn write(&self, paths: &FilePath, key: Option<&RepoKey>) -> Result<()> {
+        // Prepare the path
+        let path = object_path(&paths.commits, &self.id, key);
+
         // Write off the commit as a JSON
         // Turn the tree to JSON String format
-        let json = serde_json::to_string_pretty(&self).unwrap();
-
-        // Prepare the path
-        let path = paths.commits.join(format!("{}.json", self.id));
+        let json = serde_json::to_string_pretty(&self).at(&path)?;
 
         // Write it off
-        fs::write(path, json).unwrap();
+        write_object(&path, json.as_bytes(), key)
+    }
+
+    pub fn read(id: &str, paths: &FilePath, key: Option<&RepoKey>) -> Result<Self> {
+        // Read the commit's JSON, then parse it
+        let path = object_path(&paths.commits, id, key);
+        let json = read_object(paths, &path, "commit", id, key)?;
+        serde_json::from_str(&json).at(&path)
+    }
+
+    /// Find the full ID of a commit from a unique prefix of it
+    pub fn resolve(prefix: &str, paths: &FilePath) -> Result<Option<String>> {
+        let mut matches = fs::read_dir(&paths.commits)
+            .at(&paths.commits)?
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let name = name.strip_suffix(".enc").unwrap_or(&name);
+                name.strip_suffix(".json").map(String::from)
+            })
+            .filter(|id| id.starts_with(prefix));
+
+        // No match, or an ambiguous prefix
+        match (matches.next(), matches.next()) {
+            (Some(id), None) => Ok(Some(id)),
+            _ => Ok(None),
+        }
     }
 }
 
-/// A blob is the path to the data with its hash
+// Chunk sizes, in bytes. A chunk ends where the content says so, so that an insertion only changes
+// the chunks around it, not every one after it.
+const CHUNK_MIN: u32 = 256 * 1024;
+const CHUNK_AVG: u32 = 1024 * 1024;
+const CHUNK_MAX: u32 = 4 * 1024 * 1024;
+
+/// A blob is a file's content, as the ordered list of the chunks it's cut into
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blob {
-    pub id: Option<String>, // SHA3-256 hash of content
+    pub id: Option<String>,  // Hash of the chunk IDs
+    pub chunks: Vec<String>, // SHA3-256 hash of each chunk's content
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sparse: Option<Sparse>, // Where the chunks sit, in a file with holes
+}
+
+/// The layout of a sparse file: its holes are the gaps between its chunks, and after the last one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sparse {
+    pub offsets: Vec<u64>, // Where each chunk starts
+    pub size: u64,
 }
 
 impl Blob {
-    pub fn new(file_content: &Vec<u8>) -> Self {
-        Self {
-            id: Blob::calc_id(file_content),
-            //path,
+    /// Cut a file into chunks, and hand each one to `each_chunk` along with its ID, as it's read:
+    /// however large the file, only one chunk is held at a time. The holes of a sparse file are
+    /// skipped, not read.
+    pub fn chunk_file(
+        path: &Path,
+        key: Option<&RepoKey>,
+        mut each_chunk: impl FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<Self> {
+        let mut file = File::open(path).at(path)?;
+        let size = file.metadata().at(path)?.len();
+        let extents = data_extents(&file, size).at(path)?;
+
+        let mut chunks = Vec::new();
+        let mut offsets = Vec::new();
+        for (start, end) in &extents {
+            file.seek(SeekFrom::Start(*start)).at(path)?;
+            let extent = (&file).take(end - start);
+            for chunk in StreamCDC::new(extent, CHUNK_MIN, CHUNK_AVG, CHUNK_MAX) {
+                let chunk = chunk.map_err(io::Error::from).at(path)?;
+
+                let mut hasher = IdHasher::new(key);
+                hasher.update(&chunk.data);
+                let chunk_id = hasher.finalize();
+
+                each_chunk(&chunk_id, &chunk.data)?;
+                chunks.push(chunk_id);
+                offsets.push(start + chunk.offset);
+            }
         }
+
+        // A file that is all data is one whole extent, and needs no layout
+        let sparse = match extents.as_slice() {
+            [(0, end)] if *end == size => None,
+            [] if size == 0 => None,
+            _ => Some(Sparse { offsets, size }),
+        };
+
+        Ok(Self {
+            id: Some(Blob::calc_id(&chunks, sparse.as_ref(), key)),
+            chunks,
+            sparse,
+        })
     }
 
-    fn calc_id(file_content: &Vec<u8>) -> Option<String> {
-        // Get SHA3-256 hash of the file
+    fn calc_id(chunks: &[String], sparse: Option<&Sparse>, key: Option<&RepoKey>) -> String {
         // Create the hasher
-        let mut hasher = Sha3_256::new();
+        let mut hasher = IdHasher::new(key);
 
-        // Hash the content
-        hasher.update(file_content);
+        // The chunks' IDs, in order, stand for the content
+        for chunk_id in chunks {
+            hasher.update(chunk_id.as_bytes());
+        }
 
-        // Consume the hash
-        let file_hash = hasher.finalize();
+        // Along with where they sit, if there are holes between them
+        if let Some(sparse) = sparse {
+            for offset in &sparse.offsets {
+                hasher.update(format!(":{offset}"));
+            }
+            hasher.update(format!(":size={}", sparse.size));
+        }
 
-        // Convert it to bytes
-        let digest_bytes: &[u8] = file_hash.as_ref();
+        // Consume the hash, convert it to hexa, and return it
+        hasher.finalize()
+    }
 
-        // Convert it to hexa, and return it
-        Some(hex::encode(digest_bytes))
+    /// Read a file, and get its blob ID
+    pub fn read_id(path: &Path, key: Option<&RepoKey>) -> Result<String> {
+        let blob = Blob::chunk_file(path, key, |_, _| Ok(()))?;
+        Ok(blob.id.unwrap_or_default())
+    }
+
+    pub fn write(&self, paths: &FilePath, key: Option<&RepoKey>) -> Result<()> {
+        let id = self.id.as_deref().unwrap_or_default();
+        let path = object_path(&paths.blobs, id, key);
+        let json = serde_json::to_string_pretty(&self).at(&path)?;
+        write_object(&path, json.as_bytes(), key)
+    }
+
+    pub fn read(id: &str, paths: &FilePath, key: Option<&RepoKey>) -> Result<Self> {
+        let path = object_path(&paths.blobs, id, key);
+        let json = read_object(paths, &path, "blob", id, key)?;
+        let blob: Blob = serde_json::from_str(&json).at(&path)?;
+
+        // Named after its content, it's that content or something else put in its place
+        let calc_id = Blob::calc_id(&blob.chunks, blob.sparse.as_ref(), key);
+        if blob.id.as_deref() != Some(id) || calc_id != id {
+            return Err(OpenBrsError::AlteredObject {
+                kind: "blob",
+                id: id.to_string(),
+            });
+        }
+        Ok(blob)
+    }
+
+    /// Read back the content of the chunk `id`, checked against its id
+    pub fn read_chunk(id: &str, paths: &FilePath, key: Option<&RepoKey>) -> Result<Vec<u8>> {
+        let content = read_chunk(&paths.store, &paths.chunks, id, key)?;
+
+        let mut hasher = IdHasher::new(key);
+        hasher.update(&content);
+        match hasher.finalize() == id {
+            true => Ok(content),
+            false => Err(OpenBrsError::AlteredObject {
+                kind: "chunk",
+                id: id.to_string(),
+            }),
+        }
+    }
+
+    /// Whether the blob was written, in which case all of its chunks were stored before it
+    pub fn exists(id: &str, paths: &FilePath, key: Option<&RepoKey>) -> Result<bool> {
+        paths.store.exists(&object_path(&paths.blobs, id, key))
     }
 }
 
@@ -143,185 +492,611 @@ pub struct Tree {
     pub id: String,             // Hash of serialized tree
     pub name: String,           // Name of directory
     pub entries: Vec<EntryRef>, // IDs of contents.
+    #[serde(skip)]
+    pub subtrees: Vec<Tree>, // The trees of its directories, when it was built rather than read
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub enum EntryKind {
     File,
     Dir,
+    Symlink,
+    Fifo,
+    CharDevice,
+    BlockDevice,
+}
+
+impl EntryKind {
+    // Sockets are left out, there's nothing to restore them from
+    fn of(file_type: FileType) -> Option<Self> {
+        if file_type.is_symlink() {
+            Some(EntryKind::Symlink)
+        } else if file_type.is_dir() {
+            Some(EntryKind::Dir)
+        } else if file_type.is_file() {
+            Some(EntryKind::File)
+        } else if file_type.is_fifo() {
+            Some(EntryKind::Fifo)
+        } else if file_type.is_char_device() {
+            Some(EntryKind::CharDevice)
+        } else if file_type.is_block_device() {
+            Some(EntryKind::BlockDevice)
+        } else {
+            None
+        }
+    }
+}
+
+/// What restore reapplies to an entry, besides its content. Two are equal when all but their ctime
+/// are: restoring an entry changes its ctime, and so does reading it, with atime-preserving tools.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq)]
+pub struct EntryMetadata {
+    pub mode: u32, // Permission bits, along with setuid, setgid and sticky
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+    pub ctime: i64, // Recorded only, the system sets it
+    pub ctime_nsec: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>, // Where a symlink points to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rdev: Option<u64>, // The device number of a device node
+    // For a file with several links, the path relative to the target of the first one in the tree:
+    // the links that share it are one file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hardlink: Option<String>,
+    // Extended attributes, their values hex-encoded. POSIX ACLs (system.posix_acl_*), file
+    // capabilities (security.capability) and SELinux labels (security.selinux) are among them.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl EntryMetadata {
+    /// Read the kind and metadata of the entry at `path`, without following it if it's a symlink.
+    /// Sockets are None.
+    pub fn read(path: &Path) -> Result<Option<(EntryKind, Self)>> {
+        let metadata = fs::symlink_metadata(path).at(path)?;
+        EntryMetadata::from_stat(path, &metadata)
+    }
+
+    // The kind and metadata of the entry at `path`, from what lstat said of it
+    fn from_stat(path: &Path, metadata: &fs::Metadata) -> Result<Option<(EntryKind, Self)>> {
+        let Some(kind) = EntryKind::of(metadata.file_type()) else {
+            return Ok(None);
+        };
+
+        let link = match kind {
+            EntryKind::Symlink => Some(fs::read_link(path).at(path)?.to_string_lossy().to_string()),
+            _ => None,
+        };
+        let rdev = match kind {
+            EntryKind::CharDevice | EntryKind::BlockDevice => Some(metadata.rdev()),
+            _ => None,
+        };
+
+        Ok(Some((
+            kind,
+            Self {
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+                ctime: metadata.ctime(),
+                ctime_nsec: metadata.ctime_nsec(),
+                link,
+                rdev,
+                hardlink: None,
+                xattrs: read_xattrs(path)?,
+            },
+        )))
+    }
+
+    // Every field but the ctime goes into the id of the tree, so that it changes along with them.
+    // Trees written before the ctime was left out hashed it too, `ctime` hashes it for them.
+    fn update_id(&self, hasher: &mut IdHasher, ctime: bool) {
+        hasher.update(format!(
+            ":{:o}:{}:{}:{}.{}",
+            self.mode, self.uid, self.gid, self.mtime, self.mtime_nsec
+        ));
+        if ctime {
+            hasher.update(format!(":{}.{}", self.ctime, self.ctime_nsec));
+        }
+        if let Some(link) = &self.link {
+            hasher.update(format!(":link={link}"));
+        }
+        if let Some(rdev) = self.rdev {
+            hasher.update(format!(":rdev={rdev}"));
+        }
+        if let Some(hardlink) = &self.hardlink {
+            hasher.update(format!(":hardlink={hardlink}"));
+        }
+        for (name, value) in &self.xattrs {
+            hasher.update(format!(":xattr={name}={value}"));
+        }
+    }
+}
+
+impl PartialEq for EntryMetadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.mode == other.mode
+            && self.uid == other.uid
+            && self.gid == other.gid
+            && self.mtime == other.mtime
+            && self.mtime_nsec == other.mtime_nsec
+            && self.link == other.link
+            && self.rdev == other.rdev
+            && self.hardlink == other.hardlink
+            && self.xattrs == other.xattrs
+    }
+}
+
+// The extended attributes of an entry, a symlink's own rather than its target's. A file system that
+// doesn't support them has none.
+fn read_xattrs(path: &Path) -> Result<BTreeMap<String, String>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).at(path),
+    };
+
+    let mut xattrs = BTreeMap::new();
+    for name in names {
+        // It may have been removed since it was listed
+        if let Some(value) = xattr::get(path, &name).at(path)? {
+            xattrs.insert(name.to_string_lossy().to_string(), hex::encode(value));
+        }
+    }
+    Ok(xattrs)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct EntryRef {
     pub name: String,
-    pub path: PathBuf,
-    pub id: String,
+    pub path: PathBuf, // Relative to the target, so that trees don't depend on where it is
+    pub id: String,    // A blob's for a file, a tree's for a directory
+    pub kind: EntryKind,
+    pub metadata: EntryMetadata,
 }
 
 impl Tree {
-    pub fn build(paths: &FilePath, first_backup: bool) -> Self {
+    /// Build the tree of the target. Entries that can't be read (permission denied, or vanished
+    /// during the scan) are pushed to `skipped`. Those that are still there are kept as the
+    /// `previous` tree, if any, has them, rather than recorded as deleted; those that vanished are
+    /// left out.
+    /// In an encrypted repository, ids are hashed, and trees sealed, with `key`.
+    pub fn build(
+        paths: &FilePath,
+        previous: Option<&Tree>,
+        skipped: &mut Vec<OpenBrsError>,
+        key: Option<&RepoKey>,
+    ) -> Result<Self> {
         if paths.target.is_dir() {
-            let tree = Tree::build_dir(&paths, &paths);
-            //archive_compress_dir(&paths.target, &paths.archive);
-            tree
+            Tree::build_dir(paths, paths, previous, &mut HashMap::new(), skipped, key)
         } else {
-            let tree = Tree::build_file(paths, first_backup);
-            tree
+            Tree::build_file(paths, key)
         }
     }
 
-    fn build_dir(main_paths: &FilePath, current_paths: &FilePath) -> Self {
+    // `links` maps the files with several links found so far, by device and inode, to the first
+    // link's path relative to the target and its blob ID
+    fn build_dir(
+        main_paths: &FilePath,
+        current_paths: &FilePath,
+        previous: Option<&Tree>,
+        links: &mut HashMap<(u64, u64), (String, String)>,
+        skipped: &mut Vec<OpenBrsError>,
+        key: Option<&RepoKey>,
+    ) -> Result<Self> {
         // Create a vector for the IDs:name string pairs.
         let mut entries = Vec::new();
+        let mut subtrees = Vec::new();
 
         // Collect entries first, so the iterator (and its FD) is dropped
-        let entries_vec: Vec<_> = fs::read_dir(&current_paths.target)
-            .unwrap()
+        let mut entries_vec: Vec<_> = fs::read_dir(&current_paths.target)
+            .at(&current_paths.target)?
             .flatten()
             .map(|entry| {
-                let path = FilePath::new(&entry.path());
-                let name = entry.file_name().to_string_lossy().to_string();
-                (path, name)
+                (
+                    entry.path(),
+                    entry.file_name().to_string_lossy().to_string(),
+                )
             })
             .collect(); // <-- FD closed here
 
+        // In order, so that the first of a group of hardlinks is always the same one
+        entries_vec.sort_by(|a, b| a.1.cmp(&b.1));
+        let relative = current_paths
+            .target
+            .strip_prefix(&main_paths.target)
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+
         // Now process the collected entries
         for (path, name) in entries_vec {
-            // If it is a directory, iterate through it
-            if path.target.is_dir() {
-                // If it is a subtree, check first whether it is the .openbrs workplace
-                // If yes, skip it
-                if path.target.to_string_lossy().contains("/.openbrs") {
+            // Skip the .openbrs workplace, and a repository kept elsewhere in the target
+            if name == ".openbrs" || path == main_paths.main {
+                continue;
+            }
+
+            // What the previous snapshot holds under this name, kept if it can't be read now
+            let kept =
+                previous.and_then(|tree| tree.entries.iter().find(|entry| entry.name == name));
+
+            // It may have vanished since it was listed
+            let found = fs::symlink_metadata(&path)
+                .at(&path)
+                .and_then(|stat| Ok((EntryMetadata::from_stat(&path, &stat)?, stat)));
+            let (kind, mut metadata, stat) = match found {
+                Ok((Some((kind, metadata)), stat)) => (kind, metadata, stat),
+                Ok((None, _)) => continue,
+                Err(e) => {
+                    if !vanished(&e) {
+                        entries.extend(kept.cloned());
+                    }
+                    skipped.push(e);
                     continue;
                 }
+            };
 
-                // create a Tree instance
-                let subtree = Tree::build_dir(&main_paths, &FilePath::new(&path.target));
+            let id = match kind {
+                // A directory is a subtree, built along with its previous one, if it can be read
+                EntryKind::Dir => FilePath::new(&path)
+                    .and_then(|path| {
+                        let previous = kept
+                            .filter(|kept| kept.kind == EntryKind::Dir)
+                            .and_then(|kept| Tree::read(&kept.id, main_paths, key).ok());
+                        Tree::build_dir(main_paths, &path, previous.as_ref(), links, skipped, key)
+                    })
+                    .map(|subtree| {
+                        let id = subtree.id.clone();
+                        subtrees.push(subtree);
+                        id
+                    }),
 
-                // Push its id into our main entries variable
-                entries.push(EntryRef {
-                    name: name,
-                    path: path.target.canonicalize().unwrap(),
-                    id: subtree.id,
-                });
-            } else if path.target.is_file() {
-                // Parse the item, hash their content, to build the tree.
-                let mut file_content = Vec::new();
-                let mut file = File::open(path.target.to_path_buf()).unwrap();
-                file.read_to_end(&mut file_content).unwrap();
-
-                // Get its hash (ID)
-                let blob = Blob::new(&file_content);
-
-                // push it to the tree
-                entries.push(EntryRef {
-                    name: name,
-                    path: path.target.canonicalize().unwrap(),
-                    id: blob.id.unwrap(),
-                });
+                // Another link to a file that was hashed already is the same file
+                EntryKind::File if stat.nlink() > 1 => match links.get(&(stat.dev(), stat.ino())) {
+                    Some((first, id)) => {
+                        metadata.hardlink = Some(first.clone());
+                        Ok(id.clone())
+                    }
+                    None => Blob::read_id(&path, key).inspect(|id| {
+                        let first = relative.join(&name).to_string_lossy().to_string();
+                        metadata.hardlink = Some(first.clone());
+                        links.insert((stat.dev(), stat.ino()), (first, id.clone()));
+                    }),
+                },
+
+                // Hash the file's content, to build the tree
+                EntryKind::File => Blob::read_id(&path, key),
+
+                // The others have no content, only metadata, which the tree's id covers
+                _ => Ok(IdHasher::new(key).finalize()),
+            };
+
+            // push it to the tree
+            match id {
+                Ok(id) => entries.push(EntryRef {
+                    path: relative.join(&name),
+                    name,
+                    id,
+                    kind,
+                    metadata,
+                }),
+                Err(e) => {
+                    if !vanished(&e) {
+                        entries.extend(kept.cloned());
+                    }
+                    skipped.push(e);
+                }
             }
         }
 
         // Set the ID of the file/main target directory.
-        let id = Self::calc_dir_id(entries.clone());
+        let id = Self::calc_dir_id(entries.clone(), false, key);
 
         // Return the ID, the filename, and the entries.
         let tree = Tree {
             id,
-            name: current_paths
-                .target
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_string(),
+            name: file_name(&current_paths.target)?,
             entries,
+            subtrees,
         };
 
-        tree.write_tree(main_paths);
+        tree.write_tree(main_paths, key)?;
 
-        tree
+        Ok(tree)
     }
 
-    fn build_file(paths: &FilePath, first_backup: bool) -> Self {
-        // If it is a file, then create a blob
-        let name = paths
-            .target
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-
-        if first_backup {
-            // archive_compress_file(&paths.target, &paths.archive);
-
-            // Read the file and create a Blob instance
-            let mut file_content = Vec::new();
-            let mut file = File::open(paths.target.to_path_buf()).unwrap();
-            file.read_to_end(&mut file_content).unwrap();
+    fn build_file(paths: &FilePath, key: Option<&RepoKey>) -> Result<Self> {
+        // If it is a file, then read its blob ID and metadata
+        let name = file_name(&paths.target)?;
+        let id = Blob::read_id(&paths.target, key)?;
+        let metadata = match EntryMetadata::read(&paths.target)? {
+            Some((EntryKind::File, metadata)) => metadata,
+            _ => {
+                return Err(OpenBrsError::InvalidPath {
+                    path: paths.target.clone(),
+                    reason: "is not a regular file",
+                });
+            }
+        };
 
-            let blob_id = Blob::new(&file_content).id.unwrap();
+        let entries = vec![EntryRef {
+            name: name.clone(),
+            path: PathBuf::from(&name),
+            id,
+            kind: EntryKind::File,
+            metadata,
+        }];
 
-            // Return the ID and the tree itself
-            Tree {
-                id: blob_id.clone(),
-                name: name.clone(),
-                entries: vec![EntryRef {
-                    name,
-                    path: paths.target.canonicalize().unwrap(),
-                    id: blob_id,
-                }],
-            }
-        } else {
-            // Read file
-            let mut file_content = Vec::new();
-            let mut file = File::open(paths.target.to_path_buf()).unwrap();
-            file.read_to_end(&mut file_content).unwrap();
-
-            // Get its hash (ID)
-            let blob_id = Blob::new(&file_content).id.unwrap();
-
-            // Return the ID and the tree itself
-            Tree {
-                id: blob_id.clone(),
-                name: name.clone(),
-                entries: vec![EntryRef {
-                    name,
-                    path: paths.target.canonicalize().unwrap(),
-                    id: blob_id,
-                }],
-            }
-        }
+        // Return the ID and the tree itself
+        Ok(Tree {
+            id: Tree::calc_dir_id(entries.clone(), false, key),
+            name,
+            entries,
+            subtrees: Vec::new(),
+        })
     }
 
-    fn calc_dir_id(mut entries: Vec<EntryRef>) -> String {
+    fn calc_dir_id(mut entries: Vec<EntryRef>, ctime: bool, key: Option<&RepoKey>) -> String {
         // Create the hasher
-        let mut hasher = Sha3_256::new();
+        let mut hasher = IdHasher::new(key);
 
         // Sort it, to have determnistic IDs
         entries.sort_by(|a, b| a.name.cmp(&b.name));
 
-        // For each entry, append its name and ID to the string before hashing it.
+        // For each entry, append its name, kind, ID and metadata to the string before hashing it.
         for entry in entries {
-            hasher.update(format!("{}:{}", entry.name, entry.id))
+            hasher.update(format!("{}:{:?}:{}", entry.name, entry.kind, entry.id));
+            entry.metadata.update_id(&mut hasher, ctime);
+            hasher.update("\n");
         }
 
-        // The ID is now a hash of a serialization of FileType:Name:Id; where Name is the file/dir name, and ID
-        // is the hash of the content
-        // Encode it in base64
-        hex::encode(hasher.finalize())
+        // The ID is now a hash of a serialization of Name:Kind:Id:Metadata; where Name is the file/dir name,
+        // and ID is the hash of the content
+        // Encode it in hex
+        hasher.finalize()
     }
 
-    pub fn write_tree(&self, paths: &FilePath) {
+    pub fn write_tree(&self, paths: &FilePath, key: Option<&RepoKey>) -> Result<()> {
+        // Prepare the path
+        let path = object_path(&paths.trees, &self.id, key);
+
+        // Trees are named after their content, one that exists, maybe packed, is this one
+        if paths.store.exists(&path)? {
+            return Ok(());
+        }
+
         // Write off the tree as a JSON
         // Turn the tree to JSON String format
-        let json = serde_json::to_string_pretty(&self).unwrap();
+        let json = serde_json::to_string_pretty(&self).at(&path)?;
 
-        // Prepare the path
-        let path = paths.trees.join(format!("{}.json", &self.id));
         // Create the file
         // Write it off
-        fs::write(path, json).unwrap();
+        write_object(&path, json.as_bytes(), key)
+    }
+
+    pub fn read(id: &str, paths: &FilePath, key: Option<&RepoKey>) -> Result<Self> {
+        // Read the tree's JSON, then parse it
+        let path = object_path(&paths.trees, id, key);
+        let json = read_object(paths, &path, "tree", id, key)?;
+        let tree: Tree = serde_json::from_str(&json).at(&path)?;
+
+        // Same as a blob, a tree is named after its entries, their ctimes too for an older one
+        let named_after = |ctime| Tree::calc_dir_id(tree.entries.clone(), ctime, key) == id;
+        if tree.id != id || !(named_after(false) || named_after(true)) {
+            return Err(OpenBrsError::AlteredObject {
+                kind: "tree",
+                id: id.to_string(),
+            });
+        }
+        Ok(tree)
+    }
+
+    /// Read the tree `id`, and every tree and blob under it, each checked against its id. Those in
+    /// `seen`, by kind and id, are skipped, having been checked already; the others are added to it.
+    pub fn check(
+        id: &str,
+        paths: &FilePath,
+        seen: &mut HashSet<(EntryKind, String)>,
+        key: Option<&RepoKey>,
+    ) -> Result<()> {
+        if !seen.insert((EntryKind::Dir, id.to_string())) {
+            return Ok(());
+        }
+
+        for entry in Tree::read(id, paths, key)?.entries {
+            match entry.kind {
+                EntryKind::Dir => Tree::check(&entry.id, paths, seen, key)?,
+                EntryKind::File if seen.insert((EntryKind::File, entry.id.clone())) => {
+                    Blob::read(&entry.id, paths, key)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a tree of that id was written, i.e. the id is a directory's
+    pub fn exists(id: &str, paths: &FilePath, key: Option<&RepoKey>) -> Result<bool> {
+        paths.store.exists(&object_path(&paths.trees, id, key))
+    }
+}
+
+// Object ids are SHA3-256 hashes; in a repository that seals its trees and commits, they are
+// HMAC-SHA3-256 under its id key instead, so that an id can't confirm that a known file is backed up
+#[allow(clippy::large_enum_variant)]
+enum IdHasher {
+    Plain(Sha3_256),
+    Keyed(Hmac<Sha3_256>),
+}
+
+impl IdHasher {
+    fn new(key: Option<&RepoKey>) -> Self {
+        match key.and_then(RepoKey::id_key) {
+            Some(id_key) => IdHasher::Keyed(
+                Hmac::new_from_slice(id_key).expect("HMAC takes keys of any length"),
+            ),
+            None => IdHasher::Plain(Sha3_256::new()),
+        }
+    }
+
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            IdHasher::Plain(hasher) => Digest::update(hasher, data),
+            IdHasher::Keyed(mac) => Mac::update(mac, data.as_ref()),
+        }
+    }
+
+    fn finalize(self) -> String {
+        match self {
+            IdHasher::Plain(hasher) => hex::encode(hasher.finalize()),
+            IdHasher::Keyed(mac) => hex::encode(mac.finalize().into_bytes()),
+        }
+    }
+}
+
+// The ranges of a file that hold data, in order, found with SEEK_DATA and SEEK_HOLE. A file system
+// that can't tell where holes are has the whole file as data.
+fn data_extents(file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let seek = |offset: u64, whence: libc::c_int| -> io::Result<Option<u64>> {
+        // SAFETY: fd is an open file descriptor, which `file` keeps open for the call
+        match unsafe { libc::lseek(fd, offset as libc::off_t, whence) } {
+            -1 => match io::Error::last_os_error() {
+                // No data after the offset
+                e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                e => Err(e),
+            },
+            found => Ok(Some(found as u64)),
+        }
+    };
+
+    // The first hole is the implicit one at the end of the file, for a file without holes
+    match seek(0, libc::SEEK_HOLE) {
+        Ok(Some(hole)) if hole >= size => return Ok(vec![(0, size)]),
+        Ok(_) => {}
+        Err(_) => return Ok(vec![(0, size)]),
+    }
+
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let Some(start) = seek(offset, libc::SEEK_DATA)? else {
+            break;
+        };
+        let end = seek(start, libc::SEEK_HOLE)?.unwrap_or(size).min(size);
+        extents.push((start, end));
+        offset = end;
+    }
+    Ok(extents)
+}
+
+// Blobs, trees and commits are sealed in repositories that have an id key; older ones keep them
+// in the clear
+fn sealing_key(key: Option<&RepoKey>) -> Option<&RepoKey> {
+    key.filter(|key| key.id_key().is_some())
+}
+
+// Where a blob, a tree or a commit is stored
+fn object_path(dir: &Path, id: &str, key: Option<&RepoKey>) -> PathBuf {
+    match sealing_key(key) {
+        Some(_) => dir.join(format!("{id}.json.enc")),
+        None => dir.join(format!("{id}.json")),
+    }
+}
+
+// Write the JSON of a blob, a tree or a commit, sealed if the repository seals them
+fn write_object(path: &Path, json: &[u8], key: Option<&RepoKey>) -> Result<()> {
+    match sealing_key(key) {
+        Some(key) => {
+            let mut writer = EncryptWriter::new(File::create(path).at(path)?, key).at(path)?;
+            writer.write_all(json).at(path)?;
+            writer.finish().at(path)?;
+            Ok(())
+        }
+        None => fs::write(path, json).at(path),
+    }
+}
+
+// Read the JSON of a blob, a tree or a commit, a missing one being a broken repository
+fn read_object(
+    paths: &FilePath,
+    path: &Path,
+    kind: &'static str,
+    id: &str,
+    key: Option<&RepoKey>,
+) -> Result<String> {
+    let mut object = match paths.store.open(path) {
+        Ok(object) => object,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(OpenBrsError::MissingObject {
+                kind,
+                id: id.to_string(),
+            });
+        }
+        Err(e) => return Err(e).at(path),
+    };
+
+    let mut json = String::new();
+    let read = match sealing_key(key) {
+        Some(key) => {
+            DecryptReader::new(object, key).and_then(|mut reader| reader.read_to_string(&mut json))
+        }
+        None => object.read_to_string(&mut json),
+    };
+    match read {
+        Ok(_) => Ok(json),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            Err(OpenBrsError::Corrupted(path.to_path_buf()))
+        }
+        Err(e) => Err(e).at(path),
+    }
+}
+
+fn is_empty_dir(path: &Path) -> bool {
+    fs::read_dir(path).is_ok_and(|mut entries| entries.next().is_none())
+}
+
+// The directory a target's entries are restored into: a directory target itself, or the directory
+// holding a file target
+fn target_parent(target_path: &Path) -> Result<PathBuf> {
+    if metadata(target_path).at(target_path)?.is_dir() {
+        return Ok(target_path.to_path_buf());
+    }
+    target_path
+        .parent()
+        .map(Path::to_path_buf)
+        .ok_or(OpenBrsError::InvalidPath {
+            path: target_path.to_path_buf(),
+            reason: "has no parent directory",
+        })
+}
+
+// Whether an entry couldn't be read because it's gone, rather than e.g. because it's locked
+fn vanished(e: &OpenBrsError) -> bool {
+    matches!(e, OpenBrsError::Io { source, .. } if source.kind() == ErrorKind::NotFound)
+}
+
+// The name of a file or a directory, as stored in trees; a target given as `.` or `..` is named
+// after the directory it stands for
+fn file_name(path: &Path) -> Result<String> {
+    let name = match path.file_name() {
+        Some(name) => Some(name.to_os_string()),
+        None => path
+            .canonicalize()
+            .at(path)?
+            .file_name()
+            .map(|name| name.to_os_string()),
+    };
+    match name {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(OpenBrsError::InvalidPath {
+            path: path.to_path_buf(),
+            reason: "has no file name",
+        }),
     }
 }
 
@@ -334,6 +1109,7 @@ pub enum ChangeType {
 
 pub struct Change {
     pub change_type: ChangeType,
+    pub kind: EntryKind,
     pub name: String,
     pub path: PathBuf,
     pub old_id: Option<String>,
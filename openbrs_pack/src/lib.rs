@@ -0,0 +1,603 @@
+use openbrs_error::{OpenBrsError, Result, WithPath};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+};
+
+/// The size packs are filled up to, unless the repository's config says otherwise
+pub const DEFAULT_PACK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The objects of a repository. Backups write them loose, one file each, then gather them into
+/// packs: append-only files, each along with an index of what it holds and where.
+/// Objects are named by their path under `objects`, e.g. `chunks/<id>.chunk`, loose or packed.
+#[derive(Debug)]
+pub struct ObjectStore {
+    objects: PathBuf,
+    packs: PathBuf,
+    // Every packed object, read from the indexes the first time it's needed
+    index: RefCell<Option<HashMap<String, Location>>>,
+}
+
+// Where an object sits: in which pack, and at which bytes
+#[derive(Debug, Clone)]
+struct Location {
+    pack: PathBuf,
+    offset: u64,
+    length: u64,
+}
+
+/// The index of a pack, `<pack id>.idx` next to `<pack id>.pack`
+#[derive(Serialize, Deserialize)]
+struct PackIndex {
+    entries: Vec<PackEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PackEntry {
+    name: String,
+    offset: u64,
+    length: u64,
+}
+
+impl ObjectStore {
+    pub fn new(objects: &Path) -> Self {
+        Self {
+            objects: objects.to_path_buf(),
+            packs: objects.join("packs"),
+            index: RefCell::new(None),
+        }
+    }
+
+    /// Whether the object stored at `path`, loose, is there, loose or packed
+    pub fn exists(&self, path: &Path) -> Result<bool> {
+        if path.exists() {
+            return Ok(true);
+        }
+        Ok(self.locate(path)?.is_some())
+    }
+
+    /// Read the object stored at `path`, loose, from wherever it is. A missing object is a
+    /// NotFound error, as for a file.
+    pub fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        match File::open(path) {
+            Ok(file) => return Ok(Box::new(file)),
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+
+        let location = self
+            .locate(path)
+            .map_err(io::Error::other)?
+            .ok_or_else(|| io::Error::from(ErrorKind::NotFound))?;
+        let mut pack = File::open(&location.pack)?;
+        pack.seek(SeekFrom::Start(location.offset))?;
+        Ok(Box::new(pack.take(location.length)))
+    }
+
+    /// Gather the loose objects of `dirs` into packs of about `pack_size` bytes, then remove them.
+    /// Returns how many objects were packed; those that were packed already are only removed.
+    pub fn pack_loose(&self, dirs: &[&Path], pack_size: u64) -> Result<usize> {
+        fs::create_dir_all(&self.packs).at(&self.packs)?;
+
+        let mut writer = PackWriter::new(&self.packs, pack_size);
+        let mut packed = Vec::new();
+        for dir in dirs {
+            for entry in fs::read_dir(dir).at(dir)? {
+                let path = entry.at(dir)?.path();
+
+                // Half-written objects aren't objects yet
+                if path.extension().is_some_and(|extension| extension == "tmp") {
+                    continue;
+                }
+
+                // E.g. written again by a backup interrupted before it removed them
+                if self.locate(&path)?.is_some() {
+                    fs::remove_file(&path).at(&path)?;
+                    continue;
+                }
+
+                let content = fs::read(&path).at(&path)?;
+                writer.add(self.name(&path)?, &content)?;
+                packed.push(path);
+            }
+        }
+        writer.finish()?;
+        self.reload();
+
+        // Every object is in an indexed pack by now
+        for path in &packed {
+            fs::remove_file(path).at(path)?;
+        }
+
+        Ok(packed.len())
+    }
+
+    /// Merge the packs smaller than `pack_size` into as few ones as they fit in, leaving one copy of
+    /// each object. Returns how many packs were merged, and how many they became.
+    pub fn repack(&self, pack_size: u64) -> Result<(usize, usize)> {
+        if !self.packs.exists() {
+            return Ok((0, 0));
+        }
+
+        // A pack without an index was interrupted before being indexed, its objects are still loose;
+        // so was a half-written one
+        let mut small = Vec::new();
+        for entry in fs::read_dir(&self.packs).at(&self.packs)? {
+            let path = entry.at(&self.packs)?.path();
+            match path.extension().and_then(|extension| extension.to_str()) {
+                Some("pack") if !path.with_extension("idx").exists() => {
+                    fs::remove_file(&path).at(&path)?
+                }
+                Some("tmp") => fs::remove_file(&path).at(&path)?,
+                Some("pack") if fs::metadata(&path).at(&path)?.len() < pack_size => {
+                    small.push(path)
+                }
+                _ => {}
+            }
+        }
+        if small.len() < 2 {
+            return Ok((0, 0));
+        }
+
+        let mut writer = PackWriter::new(&self.packs, pack_size);
+        let mut seen = HashSet::new();
+        for pack in &small {
+            let mut file = File::open(pack).at(pack)?;
+            for entry in read_index(pack)? {
+                if seen.insert(entry.name.clone()) {
+                    let content = read_entry(&mut file, &entry, pack)?;
+                    writer.add(entry.name, &content)?;
+                }
+            }
+        }
+        let merged = writer.finish()?;
+
+        // The new packs are indexed, the old ones can go. Packs are named by their content, so a
+        // new pack that holds the same as an old one took its place, and stays.
+        for pack in small.iter().filter(|pack| !merged.contains(pack)) {
+            remove_pack(pack)?;
+        }
+        self.reload();
+
+        Ok((small.len(), merged.len()))
+    }
+
+    /// Rewrite every object of the packs through `rewrite`, which is given its name and content, and
+    /// gives a new content for it, or None to keep it as it is. A pack is replaced as a whole, by
+    /// packs of about `pack_size` bytes, once they're indexed, so an interrupted rewrite is picked up
+    /// where it stopped by running it again. Returns how many objects were rewritten.
+    pub fn rewrite(
+        &self,
+        pack_size: u64,
+        mut rewrite: impl FnMut(&str, &[u8]) -> Result<Option<Vec<u8>>>,
+    ) -> Result<usize> {
+        if !self.packs.exists() {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+        for pack in self.indexed_packs()? {
+            let entries = read_index(&pack)?;
+            let mut file = File::open(&pack).at(&pack)?;
+
+            // The first object to rewrite; a pack without any was rewritten before the interruption
+            let mut first = None;
+            for (index, entry) in entries.iter().enumerate() {
+                let content = read_entry(&mut file, entry, &pack)?;
+                if let Some(content) = rewrite(&entry.name, &content)? {
+                    first = Some((index, content));
+                    break;
+                }
+            }
+            let Some((first, mut first_content)) = first else {
+                continue;
+            };
+
+            // One object at a time, the ones before the first are kept as they are
+            let mut writer = PackWriter::new(&self.packs, pack_size);
+            let mut rewritten = 1;
+            for (index, entry) in entries.into_iter().enumerate() {
+                let content = match index.cmp(&first) {
+                    Ordering::Less => read_entry(&mut file, &entry, &pack)?,
+                    Ordering::Equal => mem::take(&mut first_content),
+                    Ordering::Greater => {
+                        let content = read_entry(&mut file, &entry, &pack)?;
+                        match rewrite(&entry.name, &content)? {
+                            Some(content) => {
+                                rewritten += 1;
+                                content
+                            }
+                            None => content,
+                        }
+                    }
+                };
+                writer.add(entry.name, &content)?;
+            }
+            if !writer.finish()?.contains(&pack) {
+                remove_pack(&pack)?;
+            }
+            count += rewritten;
+        }
+        self.reload();
+
+        Ok(count)
+    }
+
+    // The name of a loose object, which it keeps in packs
+    fn name(&self, path: &Path) -> Result<String> {
+        path.strip_prefix(&self.objects)
+            .map(|name| name.to_string_lossy().to_string())
+            .map_err(|_| OpenBrsError::InvalidPath {
+                path: path.to_path_buf(),
+                reason: "is outside of the objects",
+            })
+    }
+
+    fn locate(&self, path: &Path) -> Result<Option<Location>> {
+        let name = self.name(path)?;
+
+        if self.index.borrow().is_none() {
+            let mut index = HashMap::new();
+            for pack in self.indexed_packs()? {
+                for entry in read_index(&pack)? {
+                    let location = Location {
+                        pack: pack.clone(),
+                        offset: entry.offset,
+                        length: entry.length,
+                    };
+                    index.insert(entry.name, location);
+                }
+            }
+            *self.index.borrow_mut() = Some(index);
+        }
+
+        Ok(self
+            .index
+            .borrow()
+            .as_ref()
+            .and_then(|index| index.get(&name).cloned()))
+    }
+
+    // The packs that have an index
+    fn indexed_packs(&self) -> Result<Vec<PathBuf>> {
+        let mut packs = Vec::new();
+        match fs::read_dir(&self.packs) {
+            Ok(entries) => {
+                for entry in entries {
+                    let path = entry.at(&self.packs)?.path();
+                    if path.extension().is_some_and(|extension| extension == "idx") {
+                        packs.push(path.with_extension("pack"));
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).at(&self.packs),
+        }
+        Ok(packs)
+    }
+
+    // The packs changed, read their indexes again when next needed
+    fn reload(&self) {
+        *self.index.borrow_mut() = None;
+    }
+}
+
+// Fills packs one after the other, starting a new one once the current one reaches the target size
+struct PackWriter {
+    packs: PathBuf,
+    pack_size: u64,
+    current: Option<OpenPack>,
+    finished: Vec<PathBuf>,
+}
+
+struct OpenPack {
+    file: File,
+    tmp: PathBuf,
+    hasher: Sha3_256,
+    entries: Vec<PackEntry>,
+    length: u64,
+}
+
+impl PackWriter {
+    fn new(packs: &Path, pack_size: u64) -> Self {
+        Self {
+            packs: packs.to_path_buf(),
+            pack_size,
+            current: None,
+            finished: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: String, content: &[u8]) -> Result<()> {
+        let pack = match &mut self.current {
+            Some(pack) => pack,
+            None => {
+                let tmp = tmp_path(&self.packs, "pack");
+                self.current.insert(OpenPack {
+                    file: File::create(&tmp).at(&tmp)?,
+                    tmp,
+                    hasher: Sha3_256::new(),
+                    entries: Vec::new(),
+                    length: 0,
+                })
+            }
+        };
+
+        pack.file.write_all(content).at(&pack.tmp)?;
+        pack.hasher.update(content);
+        pack.entries.push(PackEntry {
+            name,
+            offset: pack.length,
+            length: content.len() as u64,
+        });
+        pack.length += content.len() as u64;
+
+        if pack.length >= self.pack_size {
+            self.seal()?;
+        }
+        Ok(())
+    }
+
+    // Finish the last pack; returns the packs that were written
+    fn finish(mut self) -> Result<Vec<PathBuf>> {
+        self.seal()?;
+        Ok(self.finished)
+    }
+
+    // Give the current pack its name, which is the hash of its content and of its index, then its
+    // index. An object is only found in a pack once both are whole.
+    fn seal(&mut self) -> Result<()> {
+        let Some(mut pack) = self.current.take() else {
+            return Ok(());
+        };
+
+        let json = serde_json::to_string(&PackIndex {
+            entries: pack.entries,
+        })
+        .at(&pack.tmp)?;
+        pack.hasher.update(json.as_bytes());
+
+        pack.file.sync_all().at(&pack.tmp)?;
+        let id = hex::encode(pack.hasher.finalize());
+        let path = self.packs.join(format!("{id}.pack"));
+        fs::rename(&pack.tmp, &path).at(&path)?;
+
+        let idx = path.with_extension("idx");
+        let idx_tmp = tmp_path(&self.packs, "idx");
+        let file = File::create(&idx_tmp).at(&idx_tmp)?;
+        (&file).write_all(json.as_bytes()).at(&idx_tmp)?;
+        file.sync_all().at(&idx_tmp)?;
+        fs::rename(&idx_tmp, &idx).at(&idx)?;
+
+        self.finished.push(path);
+        Ok(())
+    }
+}
+
+// A name of its own for a file being written, `kind` being what it will be. Another process
+// writing in the same directory picks another one.
+fn tmp_path(packs: &Path, kind: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, AtomicOrdering::Relaxed);
+    packs.join(format!("{kind}.{}.{count}.tmp", process::id()))
+}
+
+// The entries of a pack's index
+fn read_index(pack: &Path) -> Result<Vec<PackEntry>> {
+    let idx = pack.with_extension("idx");
+    let json = fs::read_to_string(&idx).at(&idx)?;
+    let pack_index: PackIndex = serde_json::from_str(&json).at(&idx)?;
+    Ok(pack_index.entries)
+}
+
+// The content of one object of a pack, read from where its index says it is
+fn read_entry(file: &mut File, entry: &PackEntry, pack: &Path) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(entry.offset)).at(pack)?;
+    let mut content = Vec::new();
+    file.take(entry.length).read_to_end(&mut content).at(pack)?;
+
+    // Cut short, the pack doesn't hold what its index says
+    match content.len() as u64 == entry.length {
+        true => Ok(content),
+        false => Err(OpenBrsError::Corrupted(pack.to_path_buf())),
+    }
+}
+
+// Remove a pack's index first, so that it's never found without its pack
+fn remove_pack(pack: &Path) -> Result<()> {
+    let idx = pack.with_extension("idx");
+    fs::remove_file(&idx).at(&idx)?;
+    fs::remove_file(pack).at(pack)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    // An empty object store of its own for each test
+    fn store(test: &str) -> (PathBuf, ObjectStore) {
+        let objects = env::temp_dir().join(format!("openbrs_pack_{}_{test}", process::id()));
+        let _ = fs::remove_dir_all(&objects);
+        fs::create_dir_all(objects.join("chunks")).unwrap();
+        let store = ObjectStore::new(&objects);
+        (objects, store)
+    }
+
+    fn write_loose(objects: &Path, name: &str, content: &[u8]) -> PathBuf {
+        let path = objects.join("chunks").join(name);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn read(store: &ObjectStore, path: &Path) -> Vec<u8> {
+        let mut content = Vec::new();
+        store.open(path).unwrap().read_to_end(&mut content).unwrap();
+        content
+    }
+
+    fn packs(store: &ObjectStore) -> usize {
+        store.indexed_packs().unwrap().len()
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let (objects, store) = store("index_round_trip");
+        let a = write_loose(&objects, "a.chunk", b"first");
+        let b = write_loose(&objects, "b.chunk", b"");
+        let c = write_loose(&objects, "c.chunk", b"third");
+        let dirs = [objects.join("chunks")];
+        let dirs: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+
+        assert_eq!(store.pack_loose(&dirs, 8).unwrap(), 3);
+        assert!(!a.exists() && !b.exists() && !c.exists());
+
+        // Read back through the indexes, as another process would
+        let store = ObjectStore::new(&objects);
+        assert_eq!(read(&store, &a), b"first");
+        assert_eq!(read(&store, &b), b"");
+        assert_eq!(read(&store, &c), b"third");
+        assert!(store.exists(&a).unwrap());
+
+        let missing = objects.join("chunks").join("d.chunk");
+        assert!(!store.exists(&missing).unwrap());
+        assert_eq!(
+            store.open(&missing).err().unwrap().kind(),
+            ErrorKind::NotFound
+        );
+
+        fs::remove_dir_all(&objects).unwrap();
+    }
+
+    #[test]
+    fn pack_loose_removes_packed_objects() {
+        let (objects, store) = store("pack_loose_removes_packed_objects");
+        let a = write_loose(&objects, "a.chunk", b"first");
+        let dirs = [objects.join("chunks")];
+        let dirs: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+        store.pack_loose(&dirs, DEFAULT_PACK_SIZE).unwrap();
+
+        // Written again, it's removed rather than packed twice
+        write_loose(&objects, "a.chunk", b"first");
+        assert_eq!(store.pack_loose(&dirs, DEFAULT_PACK_SIZE).unwrap(), 0);
+        assert!(!a.exists());
+        assert_eq!(packs(&store), 1);
+        assert_eq!(read(&store, &a), b"first");
+
+        fs::remove_dir_all(&objects).unwrap();
+    }
+
+    #[test]
+    fn repack_keeps_a_pack_it_writes_again() {
+        let (objects, store) = store("repack_keeps_a_pack_it_writes_again");
+        let a = write_loose(&objects, "a.chunk", b"first");
+        let b = write_loose(&objects, "b.chunk", b"second");
+        let dirs = [objects.join("chunks")];
+        let dirs: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+        store.pack_loose(&dirs, DEFAULT_PACK_SIZE).unwrap();
+        let name = store.name(&a).unwrap();
+
+        // A second pack holding a copy of an object of the first, which merging the two gives back
+        let mut writer = PackWriter::new(&objects.join("packs"), DEFAULT_PACK_SIZE);
+        writer.add(name, b"first").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(packs(&store), 2);
+
+        assert_eq!(store.repack(DEFAULT_PACK_SIZE).unwrap(), (2, 1));
+        assert_eq!(packs(&store), 1);
+        assert_eq!(read(&store, &a), b"first");
+        assert_eq!(read(&store, &b), b"second");
+
+        fs::remove_dir_all(&objects).unwrap();
+    }
+
+    #[test]
+    fn packs_are_named_after_their_index_too() {
+        let (objects, store) = store("packs_are_named_after_their_index_too");
+        let packs_dir = objects.join("packs");
+        fs::create_dir_all(&packs_dir).unwrap();
+
+        // The same bytes, under other names, are another pack, which doesn't replace the first
+        for name in ["chunks/a.chunk", "chunks/b.chunk"] {
+            let mut writer = PackWriter::new(&packs_dir, DEFAULT_PACK_SIZE);
+            writer.add(name.to_string(), b"same").unwrap();
+            writer.finish().unwrap();
+        }
+        assert_eq!(packs(&store), 2);
+        assert_eq!(read(&store, &objects.join("chunks/a.chunk")), b"same");
+        assert_eq!(read(&store, &objects.join("chunks/b.chunk")), b"same");
+
+        fs::remove_dir_all(&objects).unwrap();
+    }
+
+    #[test]
+    fn repack_merges_small_packs() {
+        let (objects, store) = store("repack_merges_small_packs");
+        let dirs = [objects.join("chunks")];
+        let dirs: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+        let mut loose = Vec::new();
+        for (name, content) in [
+            ("a.chunk", b"first"),
+            ("b.chunk", b"other"),
+            ("c.chunk", b"third"),
+        ] {
+            loose.push((write_loose(&objects, name, content), content));
+            store.pack_loose(&dirs, DEFAULT_PACK_SIZE).unwrap();
+        }
+        assert_eq!(packs(&store), 3);
+
+        assert_eq!(store.repack(DEFAULT_PACK_SIZE).unwrap(), (3, 1));
+        assert_eq!(packs(&store), 1);
+        for (path, content) in loose {
+            assert_eq!(read(&store, &path), content);
+        }
+
+        // Nothing is left to merge
+        assert_eq!(store.repack(DEFAULT_PACK_SIZE).unwrap(), (0, 0));
+
+        fs::remove_dir_all(&objects).unwrap();
+    }
+
+    #[test]
+    fn rewrite_replaces_packs() {
+        let (objects, store) = store("rewrite_replaces_packs");
+        let a = write_loose(&objects, "a.chunk", b"first");
+        let b = write_loose(&objects, "b.chunk", b"second");
+        let dirs = [objects.join("chunks")];
+        let dirs: Vec<&Path> = dirs.iter().map(PathBuf::as_path).collect();
+        store.pack_loose(&dirs, DEFAULT_PACK_SIZE).unwrap();
+
+        let count = store
+            .rewrite(DEFAULT_PACK_SIZE, |name, _| {
+                Ok(name.starts_with("chunks/a").then(|| b"rewritten".to_vec()))
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(packs(&store), 1);
+        assert_eq!(read(&store, &a), b"rewritten");
+        assert_eq!(read(&store, &b), b"second");
+
+        // Rewritten into packs of the size asked for, and only once
+        let count = store
+            .rewrite(1, |name, _| {
+                Ok(name.starts_with("chunks/b").then(|| b"again".to_vec()))
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(packs(&store), 2);
+        assert_eq!(read(&store, &a), b"rewritten");
+        assert_eq!(read(&store, &b), b"again");
+        assert_eq!(store.rewrite(1, |_, _| Ok(None)).unwrap(), 0);
+
+        fs::remove_dir_all(&objects).unwrap();
+    }
+}
@@ -0,0 +1,479 @@
+use glob::MatchOptions;
+pub use glob::Pattern;
+use openbrs_crypto::RepoKey;
+use openbrs_error::{OpenBrsError, Result, WithPath};
+use openbrs_main_structs::{Blob, Commit, EntryKind, EntryRef, FilePath, Tree};
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File, Permissions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt, lchown, symlink},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+/// Rebuild the target as it was at a commit. `destination` stands for the directory holding the
+/// repository: a directory target is restored as `destination` itself, a file target into it.
+/// Objects of an encrypted repository are decrypted with `key`.
+/// Returns the entries that couldn't be restored.
+pub fn restore(
+    paths: &FilePath,
+    commit_id: &str,
+    destination: &Path,
+    key: Option<&RepoKey>,
+) -> Result<Vec<OpenBrsError>> {
+    let mut skipped = Vec::new();
+
+    // The commit's tree lists every blob it needs, there's nothing else to read
+    let commit = Commit::read(commit_id, paths, key)?;
+    let tree = Tree::read(&commit.tree_id, paths, key)?;
+
+    fs::create_dir_all(destination).at(destination)?;
+    let mut links = HashMap::new();
+    restore_entries(paths, &tree, destination, key, &mut links, &mut skipped)?;
+
+    Ok(skipped)
+}
+
+/// Restore a commit over the live target. Everything is restored inside the repository first, the
+/// live target is only replaced once that succeeded, and left untouched if anything was skipped.
+pub fn restore_in_place(paths: &FilePath, commit_id: &str, key: Option<&RepoKey>) -> Result<()> {
+    // Start from a clean staging directory
+    let staging = paths.main.join("restore");
+    if staging.exists() {
+        fs::remove_dir_all(&staging).at(&staging)?;
+    }
+
+    let skipped = restore(paths, commit_id, &staging, key)?;
+    if !skipped.is_empty() {
+        return Err(OpenBrsError::IncompleteRestore {
+            count: skipped.len(),
+            staging,
+        });
+    }
+
+    // The restored entries are moved in place, which a repository kept on another file system
+    // than its target can't do; find out before anything is removed
+    let on_target = fs::metadata(&paths.parent).at(&paths.parent)?.dev();
+    if fs::metadata(&staging).at(&staging)?.dev() != on_target {
+        return Err(OpenBrsError::InvalidPath {
+            path: paths.main.clone(),
+            reason: "is on another file system than the target; restore with --to instead",
+        });
+    }
+
+    // Remove the live target, but never the repository itself
+    if paths.target.is_dir() {
+        for entry in fs::read_dir(&paths.target).at(&paths.target)? {
+            let entry = entry.at(&paths.target)?;
+            if entry.file_name() == ".openbrs" || entry.path() == paths.main {
+                continue;
+            }
+            remove_path(&entry.path())?;
+        }
+    } else {
+        remove_path(&paths.target)?;
+    }
+
+    // Move the restored entries in place, it's a rename as staging sits on the same file system
+    for entry in fs::read_dir(&staging).at(&staging)? {
+        let entry = entry.at(&staging)?;
+        let path = paths.parent.join(entry.file_name());
+        fs::rename(entry.path(), &path).at(&path)?;
+    }
+
+    fs::remove_dir(&staging).at(&staging)
+}
+
+/// An entry of a commit matched by a path or a glob
+pub struct Match {
+    pub path: PathBuf, // Relative to the target; for a file target, its name
+    pub is_dir: bool,
+}
+
+/// Find the entries of a commit matching `pattern`, a path or a glob relative to the target. A
+/// matching directory stands for its whole subtree, so nothing under it is listed on its own.
+pub fn find_matches(
+    paths: &FilePath,
+    commit_id: &str,
+    pattern: &Pattern,
+    key: Option<&RepoKey>,
+) -> Result<Vec<Match>> {
+    let commit = Commit::read(commit_id, paths, key)?;
+    let tree = Tree::read(&commit.tree_id, paths, key)?;
+
+    let mut matches = Vec::new();
+    collect_matches(paths, &tree, Path::new(""), pattern, &mut matches, key)?;
+
+    Ok(matches)
+}
+
+/// Restore only some entries of a commit, as listed by find_matches, under `destination`. Only the
+/// chunks they reference are read. Returns the entries that couldn't be restored.
+pub fn restore_matches(
+    paths: &FilePath,
+    commit_id: &str,
+    matches: &[Match],
+    destination: &Path,
+    key: Option<&RepoKey>,
+) -> Result<Vec<OpenBrsError>> {
+    let mut skipped = Vec::new();
+    let mut links = HashMap::new();
+    let commit = Commit::read(commit_id, paths, key)?;
+    let tree = Tree::read(&commit.tree_id, paths, key)?;
+
+    for entry in matches {
+        let path = destination.join(&entry.path);
+        let found = find_entry(paths, &tree, &entry.path, key).and_then(|found| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).at(parent)?;
+            }
+            Ok(found)
+        });
+        let found = match found {
+            Ok(found) => found,
+            Err(e) => {
+                skipped.push(e);
+                continue;
+            }
+        };
+
+        // Restore next to the live entry, which is only replaced once all of it was restored
+        let staged = path.with_file_name(format!(".{}.openbrs-restore", found.name));
+        remove_path(&staged)?;
+        let count = skipped.len();
+        let restored = restore_entry(paths, &found, &staged, key, &mut links, &mut skipped);
+        if restored.is_err() || skipped.len() > count {
+            remove_path(&staged)?;
+            links.retain(|_, first| !first.starts_with(&staged));
+            restored?;
+            continue;
+        }
+
+        remove_path(&path)?;
+        fs::rename(&staged, &path).at(&path)?;
+
+        // Later hardlinks link to where their first link ended up
+        for first in links.values_mut() {
+            if let Ok(rest) = first.strip_prefix(&staged) {
+                *first = if rest.as_os_str().is_empty() {
+                    path.clone()
+                } else {
+                    path.join(rest)
+                };
+            }
+        }
+    }
+
+    Ok(skipped)
+}
+
+/// Write the content of one file of a commit, as listed by find_matches, to `out`
+pub fn restore_file_to_writer(
+    paths: &FilePath,
+    commit_id: &str,
+    path: &Path,
+    out: &mut dyn Write,
+    key: Option<&RepoKey>,
+) -> Result<()> {
+    let commit = Commit::read(commit_id, paths, key)?;
+    let tree = Tree::read(&commit.tree_id, paths, key)?;
+    let entry = find_entry(paths, &tree, path, key)?;
+    if entry.kind != EntryKind::File {
+        return Err(OpenBrsError::InvalidPath {
+            path: path.to_path_buf(),
+            reason: "is not a regular file",
+        });
+    }
+
+    write_blob(paths, &entry.id, out, path, key)
+}
+
+fn collect_matches(
+    paths: &FilePath,
+    tree: &Tree,
+    prefix: &Path,
+    pattern: &Pattern,
+    matches: &mut Vec<Match>,
+    key: Option<&RepoKey>,
+) -> Result<()> {
+    // `*` must not cross directories, only `**` does
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    for entry in &tree.entries {
+        check_name(tree, entry)?;
+        let path = prefix.join(&entry.name);
+        let is_dir = entry.kind == EntryKind::Dir;
+
+        if pattern.matches_path_with(&path, options) {
+            matches.push(Match { path, is_dir });
+        } else if is_dir {
+            let subtree = Tree::read(&entry.id, paths, key)?;
+            collect_matches(paths, &subtree, &path, pattern, matches, key)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Find an entry of a commit from its path relative to the target
+fn find_entry(
+    paths: &FilePath,
+    tree: &Tree,
+    path: &Path,
+    key: Option<&RepoKey>,
+) -> Result<EntryRef> {
+    let mut tree = tree.clone();
+    let mut components = path.components().peekable();
+
+    while let Some(component) = components.next() {
+        let entry = tree
+            .entries
+            .iter()
+            .find(|entry| entry.name.as_str() == component.as_os_str())
+            .ok_or_else(|| OpenBrsError::NoMatch(path.display().to_string()))?;
+
+        if components.peek().is_none() {
+            return Ok(entry.clone());
+        }
+        tree = Tree::read(&entry.id, paths, key)?;
+    }
+
+    Err(OpenBrsError::NoMatch(path.display().to_string()))
+}
+
+// Restore every entry of a tree under `destination`, which exists. `links` maps each group of
+// hardlinks restored so far to where its first link was restored.
+fn restore_entries(
+    paths: &FilePath,
+    tree: &Tree,
+    destination: &Path,
+    key: Option<&RepoKey>,
+    links: &mut HashMap<String, PathBuf>,
+    skipped: &mut Vec<OpenBrsError>,
+) -> Result<()> {
+    for entry in &tree.entries {
+        check_name(tree, entry)?;
+        let path = destination.join(&entry.name);
+        restore_entry(paths, entry, &path, key, links, skipped)?;
+    }
+
+    Ok(())
+}
+
+// Restore a file from its chunks, a directory with everything under it, or any other entry, then
+// its metadata. A chunk that is missing or broken, or an entry that can't be written, is pushed to
+// `skipped`, the others are still restored.
+fn restore_entry(
+    paths: &FilePath,
+    entry: &EntryRef,
+    path: &Path,
+    key: Option<&RepoKey>,
+    links: &mut HashMap<String, PathBuf>,
+    skipped: &mut Vec<OpenBrsError>,
+) -> Result<()> {
+    let restored = match entry.kind {
+        EntryKind::Dir => {
+            if let Err(e) = fs::create_dir(path).at(path) {
+                skipped.push(e);
+                return Ok(());
+            }
+            let subtree = Tree::read(&entry.id, paths, key)?;
+            restore_entries(paths, &subtree, path, key, links, skipped)?;
+            Ok(())
+        }
+        EntryKind::File => restore_file(paths, entry, path, key, links),
+        EntryKind::Symlink => {
+            let link = entry.metadata.link.as_deref().unwrap_or_default();
+            symlink(link, path).at(path)
+        }
+        EntryKind::Fifo | EntryKind::CharDevice | EntryKind::BlockDevice => make_node(entry, path),
+    };
+
+    // A directory's metadata is set once everything in it is restored, else its mtime would move,
+    // and a read-only one couldn't be filled
+    if let Err(e) = restored.and_then(|()| apply_metadata(entry, path)) {
+        skipped.push(e);
+    }
+
+    Ok(())
+}
+
+// Create a FIFO or a device node; device nodes can only be created by root
+fn make_node(entry: &EntryRef, path: &Path) -> Result<()> {
+    let file_type = match entry.kind {
+        EntryKind::CharDevice => libc::S_IFCHR,
+        EntryKind::BlockDevice => libc::S_IFBLK,
+        _ => libc::S_IFIFO,
+    };
+    let mode = file_type | entry.metadata.mode as libc::mode_t;
+    let rdev = entry.metadata.rdev.unwrap_or_default() as libc::dev_t;
+
+    let c_path = c_path(path)?;
+    // SAFETY: c_path is a valid NUL-terminated string that outlives the call
+    match unsafe { libc::mknod(c_path.as_ptr(), mode, rdev) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()).at(path),
+    }
+}
+
+// Give an entry back its owner, extended attributes, permissions and mtime, in that order: changing
+// the owner clears setuid, setgid and capabilities, and a read-only file takes no attributes from
+// anyone but root. Owners, and the attributes of the security and trusted namespaces, are only given
+// back where allowed, e.g. when restoring as root; attributes only where the file system supports
+// them. An attribute that can't be set doesn't keep the rest from being applied.
+fn apply_metadata(entry: &EntryRef, path: &Path) -> Result<()> {
+    let metadata = &entry.metadata;
+    match lchown(path, Some(metadata.uid), Some(metadata.gid)) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => {}
+        owned => owned.at(path)?,
+    }
+
+    let mut failed = None;
+    for (name, value) in &metadata.xattrs {
+        let value = hex::decode(value).map_err(|_| OpenBrsError::InvalidPath {
+            path: path.to_path_buf(),
+            reason: "has a malformed extended attribute in its tree",
+        })?;
+        let privileged = name.starts_with("security.") || name.starts_with("trusted.");
+        match xattr::set(path, name, &value) {
+            Err(e) if privileged && e.raw_os_error() == Some(libc::EPERM) => {}
+            Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => {}
+            Err(e) => {
+                failed.get_or_insert(e);
+            }
+            Ok(()) => {}
+        }
+    }
+
+    // A symlink's own permissions mean nothing, and setting them would follow it
+    if entry.kind != EntryKind::Symlink {
+        fs::set_permissions(path, Permissions::from_mode(metadata.mode)).at(path)?;
+    }
+
+    // The ctime can't be set, the system does
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime as libc::time_t,
+            tv_nsec: metadata.mtime_nsec as libc::c_long,
+        },
+    ];
+    let c_path = c_path(path)?;
+    // SAFETY: c_path is a valid NUL-terminated string and times holds two timespecs, both outlive
+    // the call
+    let set = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if set != 0 {
+        return Err(io::Error::last_os_error()).at(path);
+    }
+
+    failed.map_or(Ok(()), |e| Err(e).at(path))
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| OpenBrsError::InvalidPath {
+        path: path.to_path_buf(),
+        reason: "holds a NUL byte",
+    })
+}
+
+// Restore a file as a link to the first of its hardlinks, if that one is restored already, or else
+// from its chunks. The holes of a sparse file are left as holes.
+fn restore_file(
+    paths: &FilePath,
+    entry: &EntryRef,
+    path: &Path,
+    key: Option<&RepoKey>,
+    links: &mut HashMap<String, PathBuf>,
+) -> Result<()> {
+    let first = entry.metadata.hardlink.as_ref();
+    if let Some(restored) = first.and_then(|first| links.get(first)) {
+        return fs::hard_link(restored, path).at(path);
+    }
+
+    let blob = Blob::read(&entry.id, paths, key)?;
+    let mut file = File::create(path).at(path)?;
+    match &blob.sparse {
+        Some(sparse) => {
+            for (chunk_id, offset) in blob.chunks.iter().zip(&sparse.offsets) {
+                let content = Blob::read_chunk(chunk_id, paths, key)?;
+                file.seek(SeekFrom::Start(*offset)).at(path)?;
+                file.write_all(&content).at(path)?;
+            }
+            file.set_len(sparse.size).at(path)?;
+        }
+        None => write_blob(paths, &entry.id, &mut file, path, key)?,
+    }
+
+    if let Some(first) = first {
+        links.insert(first.clone(), path.to_path_buf());
+    }
+    Ok(())
+}
+
+// Write the content of a blob to `out`, one chunk at a time, and the holes of a sparse file as
+// zeros; `path` is where it's going
+fn write_blob(
+    paths: &FilePath,
+    id: &str,
+    out: &mut dyn Write,
+    path: &Path,
+    key: Option<&RepoKey>,
+) -> Result<()> {
+    let blob = Blob::read(id, paths, key)?;
+    let mut written = 0;
+    for (index, chunk_id) in blob.chunks.iter().enumerate() {
+        let content = Blob::read_chunk(chunk_id, paths, key)?;
+        if let Some(offset) = blob.sparse.as_ref().and_then(|s| s.offsets.get(index)) {
+            written += write_zeros(out, offset.saturating_sub(written), path)?;
+        }
+        out.write_all(&content).at(path)?;
+        written += content.len() as u64;
+    }
+    if let Some(sparse) = &blob.sparse {
+        write_zeros(out, sparse.size.saturating_sub(written), path)?;
+    }
+
+    Ok(())
+}
+
+fn write_zeros(out: &mut dyn Write, count: u64, path: &Path) -> Result<u64> {
+    io::copy(&mut io::repeat(0).take(count), out).at(path)
+}
+
+// Names come from the tree, and must be plain ones: never write outside of the destination
+fn check_name(tree: &Tree, entry: &EntryRef) -> Result<()> {
+    let mut components = Path::new(&entry.name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(OpenBrsError::UnsafeName {
+            tree: tree.id.clone(),
+            name: entry.name.clone(),
+        }),
+    }
+}
+
+// Remove a file or a directory, if there's anything there. Symlinks are removed, not followed.
+fn remove_path(path: &Path) -> Result<()> {
+    match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).at(path),
+        Ok(_) => fs::remove_file(path).at(path),
+        Err(_) => Ok(()),
+    }
+}
@@ -1,24 +1,137 @@
-use openbrs_archv_cmprss::archive_compress;
-use openbrs_main_structs::{Change, ChangeType, FilePath};
-use std::env;
-pub fn stage(changes: Vec<Change>, paths: &FilePath) {
+use openbrs_archv_cmprss::{Compression, store_chunk};
+use openbrs_crypto::RepoKey;
+use openbrs_error::{OpenBrsError, Result};
+use openbrs_main_structs::{Blob, Change, ChangeType, EntryKind, FilePath, Tree};
+use std::path::Path;
+
+/// Store the chunks and blobs of what was added or modified. Returns what couldn't be stored; a change that
+/// can't be stored at all (e.g. it vanished since the scan) doesn't stop the others.
+/// Chunks are compressed as `compression` says, and encrypted with `key` if the repository is
+/// encrypted.
+pub fn stage(
+    changes: Vec<Change>,
+    paths: &FilePath,
+    compression: &Compression,
+    key: Option<&RepoKey>,
+) -> Result<Vec<OpenBrsError>> {
+    let mut skipped = Vec::new();
+
     // Parse changes
     for change in changes {
-        println!("{}", change.name);
         if change.name == ".openbrs" {
             continue;
         }
         // Match changes, to stage what was added and what was modified only.
         match change.change_type {
             ChangeType::Added | ChangeType::Modified => {
-                // One issue: I have the target's absolute path, not relative path.
-                // Solution: get the relative path according to the current working directory.
-                // CWD = Current Working Directory
-                let cwd = env::current_dir().unwrap();
-                let target_relative_path = change.path.strip_prefix(cwd).unwrap();
-                archive_compress(&target_relative_path.to_path_buf(), &paths.blobs);
+                let id = change.new_id.unwrap_or_default();
+
+                match change.kind {
+                    // A modified directory's changes are listed on their own, an added one's aren't
+                    EntryKind::Dir if change.change_type == ChangeType::Added => {
+                        let tree = Tree::read(&id, paths, key)?;
+                        skipped.extend(stage_tree(&tree, paths, compression, key)?);
+                    }
+                    EntryKind::File => {
+                        if let Err(e) = stage_file(
+                            &paths.entry_path(&change.path),
+                            &id,
+                            paths,
+                            compression,
+                            key,
+                        ) {
+                            skipped.push(e);
+                        }
+                    }
+                    // The others are only metadata, which the tree holds
+                    _ => {}
+                }
             }
             ChangeType::Removed => {}
         }
     }
+
+    Ok(skipped)
+}
+
+/// Store the blob of every file of a tree, down its subtrees, that isn't stored yet. The subtrees
+/// of a tree that was just built are taken as they are, as a host sealing to recipients can't read
+/// back the ones it wrote.
+pub fn stage_tree(
+    tree: &Tree,
+    paths: &FilePath,
+    compression: &Compression,
+    key: Option<&RepoKey>,
+) -> Result<Vec<OpenBrsError>> {
+    let mut skipped = Vec::new();
+
+    for entry in &tree.entries {
+        match entry.kind {
+            EntryKind::Dir => {
+                let staged = match tree.subtrees.iter().find(|subtree| subtree.id == entry.id) {
+                    Some(subtree) => stage_tree(subtree, paths, compression, key)?,
+                    None => {
+                        let subtree = Tree::read(&entry.id, paths, key)?;
+                        stage_tree(&subtree, paths, compression, key)?
+                    }
+                };
+                skipped.extend(staged);
+            }
+            EntryKind::File => {
+                if let Err(e) = stage_file(
+                    &paths.entry_path(&entry.path),
+                    &entry.id,
+                    paths,
+                    compression,
+                    key,
+                ) {
+                    skipped.push(e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(skipped)
+}
+
+// Store a file under the blob ID the tree recorded for it; a file that is the same as one stored
+// already, wherever it was, isn't even read. Of a file that changed, only the chunks that aren't
+// stored yet are. A file that won't shrink, judging by its name and first chunk, is stored
+// uncompressed.
+fn stage_file(
+    path: &Path,
+    id: &str,
+    paths: &FilePath,
+    compression: &Compression,
+    key: Option<&RepoKey>,
+) -> Result<()> {
+    if Blob::exists(id, paths, key)? {
+        return Ok(());
+    }
+
+    let mut compresses = None;
+    let blob = Blob::chunk_file(path, key, |chunk_id, content| {
+        let compresses = *compresses.get_or_insert_with(|| compression.compresses(path, content));
+        store_chunk(
+            content,
+            &paths.store,
+            &paths.chunks,
+            chunk_id,
+            compresses.then_some(compression),
+            key,
+        )
+    })?;
+
+    // The file may have changed since the scan, and a blob must hold what its ID says. The chunks
+    // stored meanwhile are of no harm.
+    if blob.id.as_deref() != Some(id) {
+        return Err(OpenBrsError::InvalidPath {
+            path: path.to_path_buf(),
+            reason: "changed during the backup",
+        });
+    }
+
+    // Written last, so that a blob that exists has all of its chunks
+    blob.write(paths, key)
 }